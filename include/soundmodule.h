@@ -2,6 +2,7 @@
 #define __soundmodule_h
 #include <stdint.h>
 #include <stddef.h>
+#include <stdbool.h>
 
 #ifdef __cplusplus
 extern "C" {
@@ -11,6 +12,21 @@ extern "C" {
 // Opaque pointer to user-supplied AlgoParamSet tree
 typedef void AlgoParamSet;
 
+/// Parameter tapers (AlgoCParam.taper). taper_param holds the exponent, center value or step count.
+#define ALGO_TAPER_LINEAR       0
+#define ALGO_TAPER_LOGARITHMIC  1   // value = min * (max/min)^n
+#define ALGO_TAPER_EXPONENTIAL  2   // value = min + (max-min) * n^taper_param
+#define ALGO_TAPER_SKEW         3   // power curve with value taper_param at n = 0.5
+#define ALGO_TAPER_STEPPED      4   // taper_param evenly spaced positions
+
+/// Parameter flags (AlgoCParam.flags)
+#define ALGO_PARAM_READ_ONLY        (1u << 0)   // output of the algorithm, writes are ignored
+#define ALGO_PARAM_HIDDEN           (1u << 1)   // internal, not shown in generic UIs
+#define ALGO_PARAM_NON_AUTOMATABLE  (1u << 2)
+#define ALGO_PARAM_METER            (1u << 3)   // level or gain reduction display
+#define ALGO_PARAM_GLOBAL           (1u << 4)   // changes other parameters
+#define ALGO_PARAM_RAMPABLE         (1u << 5)   // the module smooths changes itself
+
 /// C representation of a parameter
 typedef struct {
     const char *key;   // Identifier for the parameter
@@ -19,7 +35,12 @@ typedef struct {
     float max;         // Maximum value
     float defvalue;
     int32_t dtype;     // Unit or data type code
-    const char ** dependents;
+    const char ** dependents;     // NULL terminated keypaths relative to the parameter set holding this parameter, or NULL
+    const char ** value_strings;  // NULL terminated labels for each integer value in min..max, or NULL
+    const char *unit_name;        // Label for custom units (dtype CUSTOMUNIT), or NULL
+    int32_t taper;                // ALGO_TAPER_*
+    float taper_param;
+    uint32_t flags;               // ALGO_PARAM_*
 } AlgoCParam;
 
 typedef struct {
@@ -27,6 +48,37 @@ typedef struct {
     const char *name;  // Human-readable name
 } AlgoCParamSet;
 
+/// Algorithm categories (AlgoCDescriptor.category)
+#define ALGO_CATEGORY_EFFECT      0
+#define ALGO_CATEGORY_INSTRUMENT  1
+#define ALGO_CATEGORY_MIDIEFFECT  2
+#define ALGO_CATEGORY_ANALYZER    3
+
+/// Kind of unique identifier (AlgoCDescriptor.id_kind)
+#define ALGO_ID_NONE    0
+#define ALGO_ID_FOURCC  1   // id[0..4] holds the four-char code, the rest is zero
+#define ALGO_ID_UUID    2   // id holds the 16 UUID bytes
+
+/// Feature bits (AlgoCDescriptor.features)
+#define ALGO_FEATURE_AUDIO_INPUT   (1u << 0)
+#define ALGO_FEATURE_AUDIO_OUTPUT  (1u << 1)
+#define ALGO_FEATURE_MIDI_INPUT    (1u << 2)
+#define ALGO_FEATURE_MIDI_OUTPUT   (1u << 3)
+#define ALGO_FEATURE_SIDECHAIN     (1u << 4)
+
+/// C representation of an algorithm descriptor
+typedef struct {
+    const char *name;        // Display name
+    const char *vendor;      // Vendor name
+    uint32_t version_major;  // Semantic version
+    uint32_t version_minor;
+    uint32_t version_patch;
+    int32_t category;        // ALGO_CATEGORY_*
+    int32_t id_kind;         // ALGO_ID_*
+    uint8_t id[16];          // Unique identifier
+    uint32_t features;       // ALGO_FEATURE_* bits
+} AlgoCDescriptor;
+
 /// Sentinel value returned in *basekey when no further element is found
 #define ALGOPARAM_KEY_NOT_FOUND ((uint64_t)(-1))
 
@@ -51,6 +103,47 @@ AlgoCParam algoparam_get_first_param(const AlgoParamSet *tree, uint64_t *basekey
 AlgoCParam algoparam_get_next_param(const AlgoParamSet *tree, uint64_t *basekey);
 
 
+/// Parameter changes reported by the algorithm itself (AlgoCParamChange.kind)
+#define ALGO_CHANGE_VALUE          0
+#define ALGO_CHANGE_BEGIN_GESTURE  1   // the algorithm starts changing the parameter continuously
+#define ALGO_CHANGE_END_GESTURE    2
+#define ALGO_CHANGE_TREE_CHANGED   3   // the parameter set at address has been rebuilt, get the tree again
+
+typedef struct {
+    int32_t kind;                 // ALGO_CHANGE_*
+    uint64_t address;
+    float value;                  // new value for ALGO_CHANGE_VALUE
+} AlgoCParamChange;
+
+typedef void (*soundmodule_change_callback)(void* context, AlgoCParamChange change);
+
+/// Thread contract for SoundModule functions
+///
+/// Control functions (soundmodule_get_params, soundmodule_get_tree_generation, soundmodule_get_descriptor, soundmodule_set_parameter,
+/// soundmodule_get_parameter(s), the batch functions and the algoparam_* tree walks) may be called from any non-audio thread,
+/// also concurrently. Parameter changes reach the audio thread through atomic storage.
+///
+/// Render functions (soundmodule_init, soundmodule_run, soundmodule_send_midi) belong to the audio thread
+/// and must not overlap each other. They may overlap control functions.
+///
+/// soundmodule_is_faulted, soundmodule_schedule_parameter and soundmodule_get_sample_time may be called from
+/// any thread, including the audio thread. soundmodule_release must not overlap any other call.
+
+/// @brief Creates a SoundModule running a registered algorithm
+/// @param name Name the algorithm was registered with
+/// @return New SoundModule, or NULL if the name is unknown (see soundmodule_last_error). Free with soundmodule_release.
+void* soundmodule_create(const char* name);
+
+/// @brief Lists the registered algorithms
+/// @return NULL terminated array of names, valid for the lifetime of the process. Algorithms registered
+///         later only appear in arrays returned by later calls.
+const char** soundmodule_list_algorithms(void);
+
+/// @brief Describes a registered algorithm without creating a module
+/// @param name Name the algorithm was registered with
+/// @return Descriptor, with NULL name if the algorithm is unknown. Strings are valid for the lifetime of the process.
+AlgoCDescriptor soundmodule_describe_algorithm(const char* name);
+
 /// @brief Initializes a SoundModule object
 void soundmodule_init(void* self, int32_t fs);
 
@@ -60,9 +153,21 @@ void soundmodule_release(void* self);
 
 /// @brief Gets a parameter tree pointer (non-owned)
 /// @param self 
-/// @return &self.param
+/// @return The current tree. It stays valid until the next call, also if the algorithm replaces part of
+/// the tree in the meantime (see ALGO_CHANGE_TREE_CHANGED and soundmodule_get_tree_generation).
 void* soundmodule_get_params(void* self);
 
+/// @brief Gets the parameter tree generation
+/// @param self SoundModule
+/// @return Starts at 0 and increments whenever the algorithm replaces part of the tree. Addresses from an
+/// older generation may refer to other parameters, rebuild the parameter model with soundmodule_get_params.
+uint64_t soundmodule_get_tree_generation(void* self);
+
+/// @brief Gets the descriptor of the algorithm running in the module
+/// @param self SoundModule
+/// @return Descriptor. Strings are owned by the module.
+AlgoCDescriptor soundmodule_get_descriptor(void* self);
+
 /// @brief Sends midi data to the sound module
 /// @param self SoundModule
 /// @param data midi data
@@ -73,8 +178,12 @@ void soundmodule_send_midi(void* self, uint8_t* data, size_t len, uint64_t times
 /// @brief Sets a parameter in the module
 /// @param self SoundModule
 /// @param address Address of the parameter
-/// @param value Value of the parameter
-void soundmodule_set_parameter(void* self, uint64_t address, float value);
+/// @param value Value of the parameter. It is clamped to min..max, NaN is replaced by the default and
+/// INDEXED/BOOLEAN values are rounded. Ignored for ALGO_PARAM_READ_ONLY parameters. Dependents whose values
+/// change as a result are reported as ALGO_CHANGE_VALUE changes (see soundmodule_poll_changes).
+/// @return The value that was applied, the current value if the write was refused, or NaN if the setter
+/// panicked (see soundmodule_is_faulted)
+float soundmodule_set_parameter(void* self, uint64_t address, float value);
 
 /// @brief Gets a parameter in the module
 /// @param self SoundModule
@@ -82,6 +191,126 @@ void soundmodule_set_parameter(void* self, uint64_t address, float value);
 /// @return Value of the parameter
 float soundmodule_get_parameter(void* self, uint64_t address);
 
+/// @brief Schedules a sample accurate parameter change. soundmodule_run splits its block at the event and
+/// ramps in steps of 32 samples. Up to 1024 events may be pending. Blocks with more than 8 input or output
+/// channels are not split: events falling inside them apply at the block start and ramps step once per block.
+/// @param self SoundModule
+/// @param address Address of the parameter
+/// @param value Target value
+/// @param sample_time Sample at which the change starts, counted in samples rendered since the module was
+/// created (see soundmodule_get_sample_time). Times before the next block apply at its start.
+/// @param ramp Number of samples to move from the current value to value, 0 to jump
+/// Events are dropped if the algorithm changes the tree before they apply (see ALGO_CHANGE_TREE_CHANGED),
+/// reschedule them with addresses from the new tree.
+/// @return false if the address is unknown or read-only, or too many events are pending
+bool soundmodule_schedule_parameter(void* self, uint64_t address, float value, uint64_t sample_time, uint32_t ramp);
+
+/// @brief Gets the render position
+/// @param self SoundModule
+/// @return Sample time of the first sample of the next block
+uint64_t soundmodule_get_sample_time(void* self);
+
+/// @brief Takes parameter changes the algorithm made itself (MIDI learn, randomize, dependents) from the
+/// change queue. Up to 1024 changes are kept, further ones are dropped.
+/// @param self SoundModule
+/// @param changes Receives up to max changes
+/// @param max Size of changes
+/// @return Number of changes copied
+size_t soundmodule_poll_changes(void* self, AlgoCParamChange *changes, size_t max);
+
+/// @brief Delivers changes to a callback instead of the change queue. The callback runs on the thread
+/// the algorithm reports from, which may be the audio thread, so it must not block. It may be called
+/// from several threads at once. Changes reported while the callback is being replaced go to the queue.
+/// @param self SoundModule
+/// @param callback Callback, or NULL to go back to queueing
+/// @param context Passed to the callback
+void soundmodule_set_change_callback(void* self, soundmodule_change_callback callback, void* context);
+
+/// @brief Starts a parameter batch. All values of a committed batch are applied together at the start of
+/// the next block soundmodule_run renders, e.g. to load a preset without rendering a mix of old and new values.
+/// @param self SoundModule
+/// @return Batch owned by the caller until soundmodule_commit_batch or soundmodule_discard_batch
+void* soundmodule_begin_batch(void* self);
+
+/// @brief Adds a value to a batch. The value is dropped if the algorithm changes the tree before the batch
+/// is applied (see ALGO_CHANGE_TREE_CHANGED).
+/// @param self SoundModule
+/// @param batch Batch from soundmodule_begin_batch
+/// @param address Address of the parameter
+/// @param value Value of the parameter, clamped and rounded like soundmodule_set_parameter when applied
+/// @return false if the address is unknown or read-only
+bool soundmodule_batch_set(void* self, void* batch, uint64_t address, float value);
+
+/// @brief Hands a batch to the audio thread. Takes ownership of the batch, also on failure.
+/// soundmodule_get_parameter returns the old values until the next block has been rendered.
+/// @param self SoundModule
+/// @param batch Batch from soundmodule_begin_batch
+/// @return false if too many batches are waiting for the audio thread
+bool soundmodule_commit_batch(void* self, void* batch);
+
+/// @brief Frees a batch without applying it
+/// @param batch Batch from soundmodule_begin_batch
+void soundmodule_discard_batch(void* batch);
+
+/// @brief Sets parameters back to their declared defaults. New modules start out at their defaults.
+/// @param self SoundModule
+/// @param address Address of a parameter or parameter set, ALGOPARAM_KEY_NOT_FOUND for all parameters
+/// @return Number of parameters reset. Read-only parameters are skipped.
+size_t soundmodule_reset_to_defaults(void* self, uint64_t address);
+
+/// @brief Gets several parameters at once, e.g. to poll meters (ALGO_PARAM_METER) from a UI timer
+/// @param self SoundModule
+/// @param addresses count parameter addresses
+/// @param values Receives count values, 0 for unknown addresses
+/// @param count Number of addresses
+/// @return Number of known addresses
+size_t soundmodule_get_parameters(void* self, const uint64_t *addresses, float *values, size_t count);
+
+/// @brief Maps a parameter value to the normalized 0..1 range using the parameter's taper
+/// @param self SoundModule
+/// @param address Address of the parameter
+/// @param value Value in min..max
+/// @return Normalized value, 0 if the address is unknown
+float soundmodule_to_normalized(void* self, uint64_t address, float value);
+
+/// @brief Maps a normalized 0..1 value to the parameter range using the parameter's taper
+/// @param self SoundModule
+/// @param address Address of the parameter
+/// @param normalized Value in 0..1
+/// @return Value in min..max, 0 if the address is unknown
+float soundmodule_from_normalized(void* self, uint64_t address, float normalized);
+
+/// @brief Formats a parameter value for display, e.g. "1.2 kHz" or "-6.0 dB"
+/// @param self SoundModule
+/// @param address Address of the parameter
+/// @param value Value to format
+/// @param buf Destination, receives a null terminated (possibly truncated) string. May be NULL.
+/// @param len Size of buf in bytes
+/// @return Full length of the string excluding the terminator, 0 if the address is unknown
+size_t soundmodule_format_parameter(void* self, uint64_t address, float value, char* buf, size_t len);
+
+/// @brief Parses text entered for a parameter, e.g. "1.2k", "C#4" or "1/8 dotted"
+/// @param self SoundModule
+/// @param address Address of the parameter
+/// @param text Null terminated input
+/// @param value Receives the parsed value
+/// @return true if the text could be parsed
+bool soundmodule_parse_parameter(void* self, uint64_t address, const char* text, float* value);
+
+/// @brief Checks whether the module has faulted
+/// @param self SoundModule
+/// @return true if the algorithm panicked. A faulted module outputs silence until it is released.
+bool soundmodule_is_faulted(void* self);
+
+/// @brief Copies the last error message into a caller supplied buffer
+/// @param buf Destination, receives a null terminated (possibly truncated) message. May be NULL.
+/// @param len Size of buf in bytes
+/// @return Full length of the message excluding the terminator, 0 if there is no error
+size_t soundmodule_last_error(char* buf, size_t len);
+
+/// @brief Clears the last error message
+void soundmodule_clear_error(void);
+
 /// @brief Core of the module
 /// @param self Sound module
 /// @param lo left output
@@ -0,0 +1,333 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Expr, Fields, Ident, LitStr};
+
+// #[derive(Params)] generates soundmodule::algoparam::Params and Default for a parameter struct.
+//
+//     #[derive(Params)]
+//     struct Filter {
+//         #[param(id = "cutoff", name = "Cutoff", min = 20.0, max = 20000.0, default = 1000.0, unit = HERTZ)]
+//         cutoff: AtomicF32,
+//         #[params(id = "env", name = "Envelope")]
+//         env: Arc<Envelope>,
+//     }
+//
+// #[param] fields must implement ParamStorage (AtomicF32, Smooth) and become parameters of the set, #[params] fields hold a nested
+// Params struct in an Arc and become subsets. Other fields are left alone and initialized with Default.
+// id defaults to the field name, name to the id, min/max to 0..1 (0..count-1 with value_strings = [...]),
+// default to min and unit to GENERIC. unit_name = "..." makes it a CUSTOMUNIT parameter with that label,
+// taper = Logarithmic (or any Taper) sets the normalized mapping and flags = [READ_ONLY, METER] the AlgoParamFlags.
+// Ranges, defaults, value_strings counts and tapers given as literals are checked at compile time.
+
+#[proc_macro_derive(Params, attributes(param, params))]
+pub fn derive_params(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+struct ParamAttr {
+    id: Option<LitStr>,
+    name: Option<LitStr>,
+    min: Option<Expr>,
+    max: Option<Expr>,
+    default: Option<Expr>,
+    unit: Option<Ident>,
+    unit_name: Option<LitStr>,
+    taper: Option<Expr>,
+    flags: Vec<Ident>,
+    dependents: Vec<LitStr>,
+    value_strings: Vec<LitStr>,
+}
+
+struct SetAttr {
+    id: Option<LitStr>,
+    name: Option<LitStr>,
+}
+
+enum FieldKind {
+    Param(Box<ParamAttr>),
+    Set(SetAttr),
+    Plain,
+}
+
+// [A, B, ...]
+fn parse_ident_list(input: syn::parse::ParseStream) -> syn::Result<Vec<Ident>> {
+    let content;
+    syn::bracketed!(content in input);
+    let list = content.parse_terminated(|input| input.parse::<Ident>(), syn::Token![,])?;
+    Ok(list.into_iter().collect())
+}
+
+// ["a", "b", ...]
+fn parse_str_list(input: syn::parse::ParseStream) -> syn::Result<Vec<LitStr>> {
+    let content;
+    syn::bracketed!(content in input);
+    let list = content.parse_terminated(|input| input.parse::<LitStr>(), syn::Token![,])?;
+    Ok(list.into_iter().collect())
+}
+
+fn parse_field(field: &syn::Field) -> syn::Result<FieldKind> {
+    let mut kind = FieldKind::Plain;
+    for attr in &field.attrs {
+        if attr.path().is_ident("param") {
+            if !matches!(kind, FieldKind::Plain) {
+                return Err(syn::Error::new(attr.span(), "field has more than one #[param]/#[params] attribute"));
+            }
+            let mut p = ParamAttr { id: None, name: None, min: None, max: None, default: None, unit: None, unit_name: None, taper: None, flags: Vec::new(), dependents: Vec::new(), value_strings: Vec::new() };
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("id") {
+                    p.id = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("name") {
+                    p.name = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("min") {
+                    p.min = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("max") {
+                    p.max = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("default") {
+                    p.default = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("unit") {
+                    p.unit = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("unit_name") {
+                    p.unit_name = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("taper") {
+                    p.taper = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("flags") {
+                    p.flags = parse_ident_list(meta.value()?)?;
+                } else if meta.path.is_ident("dependents") {
+                    p.dependents = parse_str_list(meta.value()?)?;
+                } else if meta.path.is_ident("value_strings") {
+                    p.value_strings = parse_str_list(meta.value()?)?;
+                } else {
+                    return Err(meta.error("unknown #[param] key, expected id, name, min, max, default, unit, unit_name, taper, flags, dependents or value_strings"));
+                }
+                Ok(())
+            })?;
+            kind = FieldKind::Param(Box::new(p));
+        } else if attr.path().is_ident("params") {
+            if !matches!(kind, FieldKind::Plain) {
+                return Err(syn::Error::new(attr.span(), "field has more than one #[param]/#[params] attribute"));
+            }
+            let mut s = SetAttr { id: None, name: None };
+            // A bare #[params] is allowed
+            if !matches!(attr.meta, syn::Meta::Path(_)) {
+                attr.parse_nested_meta(|meta| {
+                    if meta.path.is_ident("id") {
+                        s.id = Some(meta.value()?.parse()?);
+                    } else if meta.path.is_ident("name") {
+                        s.name = Some(meta.value()?.parse()?);
+                    } else {
+                        return Err(meta.error("unknown #[params] key, expected id or name"));
+                    }
+                    Ok(())
+                })?;
+            }
+            kind = FieldKind::Set(s);
+        }
+    }
+    Ok(kind)
+}
+
+// The value of a number literal like 20.0, -96 or (1.5)
+fn literal_f32(expr: &Expr) -> Option<f32> {
+    match expr {
+        Expr::Lit(syn::ExprLit { lit: syn::Lit::Float(lit), .. }) => lit.base10_parse().ok(),
+        Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(lit), .. }) => lit.base10_parse().ok(),
+        Expr::Unary(syn::ExprUnary { op: syn::UnOp::Neg(_), expr, .. }) => literal_f32(expr).map(|v| -v),
+        Expr::Paren(syn::ExprParen { expr, .. }) | Expr::Group(syn::ExprGroup { expr, .. }) => literal_f32(expr),
+        _ => None,
+    }
+}
+
+// Reports at compile time what AlgoParam would panic on at construction, as far as it is given as literals.
+// Values given as other expressions are checked when the set is built.
+fn check_param(p: &ParamAttr, span: proc_macro2::Span, implied_max: f32) -> syn::Result<()> {
+    let strings = p.id.iter().chain(&p.name).chain(&p.unit_name).chain(&p.dependents).chain(&p.value_strings);
+    if let Some(lit) = strings.into_iter().find(|lit| lit.value().contains('\0')) {
+        return Err(syn::Error::new(lit.span(), "string contains a null byte"));
+    }
+    let span_of = |expr: &Option<Expr>| expr.as_ref().map_or(span, |e| e.span());
+    let min = p.min.as_ref().map_or(Some(0.0), literal_f32);
+    let max = p.max.as_ref().map_or(Some(implied_max), literal_f32);
+    let (Some(min), Some(max)) = (min, max) else { return Ok(()) };
+    if min > max {
+        return Err(syn::Error::new(span_of(&p.max), format!("min {} is above max {}", min, max)));
+    }
+    if let Some(default) = p.default.as_ref().and_then(literal_f32) && !(min..=max).contains(&default) {
+        return Err(syn::Error::new(span_of(&p.default), format!("default {} is outside {}..{}", default, min, max)));
+    }
+    if !p.value_strings.is_empty() {
+        let expected = (min.fract() == 0.0 && max.fract() == 0.0).then(|| (max - min) as usize + 1);
+        if expected != Some(p.value_strings.len()) {
+            let message = match expected {
+                Some(expected) => format!("{} value_strings given, the range {}..{} needs {}", p.value_strings.len(), min, max, expected),
+                None => format!("value_strings need an integer range, not {}..{}", min, max),
+            };
+            return Err(syn::Error::new(p.value_strings[0].span(), message));
+        }
+    }
+    if let Some(taper) = &p.taper && let Some(message) = check_taper(taper, min, max) {
+        return Err(syn::Error::new(taper.span(), message));
+    }
+    Ok(())
+}
+
+// Mirrors Taper::is_valid_for for tapers written with literal arguments
+fn check_taper(taper: &Expr, min: f32, max: f32) -> Option<String> {
+    let variant = |path: &syn::Path| path.segments.last().map(|s| s.ident.to_string());
+    match taper {
+        Expr::Path(path) if variant(&path.path).as_deref() == Some("Logarithmic") => {
+            (min <= 0.0 || max <= min).then(|| format!("Logarithmic taper needs 0 < min < max, not {}..{}", min, max))
+        }
+        Expr::Call(call) => {
+            let Expr::Path(func) = call.func.as_ref() else { return None };
+            let arg = call.args.first().and_then(literal_f32)?;
+            match variant(&func.path).as_deref() {
+                Some("Exponential") => (arg <= 0.0).then(|| format!("Exponential taper needs a positive exponent, not {}", arg)),
+                Some("Stepped") => (arg < 2.0).then(|| format!("Stepped taper needs at least 2 steps, not {}", arg)),
+                _ => None,
+            }
+        }
+        Expr::Struct(st) if variant(&st.path).as_deref() == Some("Skew") => {
+            let center = st.fields.iter().find(|f| matches!(&f.member, syn::Member::Named(n) if n == "center")).and_then(|f| literal_f32(&f.expr))?;
+            (center <= min || center >= max).then(|| format!("Skew taper needs its center inside {}..{}, not {}", min, max, center))
+        }
+        _ => None,
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new(input.span(), "#[derive(Params)] needs a struct with named fields")),
+        },
+        _ => return Err(syn::Error::new(input.span(), "#[derive(Params)] only supports structs")),
+    };
+
+    let krate = quote!(::soundmodule);
+    let mut children = Vec::new();
+    let mut inits = Vec::new();
+
+    for field in fields {
+        let field_ident = field.ident.as_ref().expect("named field");
+        let field_name = LitStr::new(&field_ident.to_string(), field_ident.span());
+        match parse_field(field)? {
+            FieldKind::Param(p) => {
+                // Value strings imply 0..count-1, like AlgoParamBuilder::value_strings
+                let implied_max = if p.value_strings.is_empty() { 1.0 } else { (p.value_strings.len() - 1) as f32 };
+                check_param(&p, field.span(), implied_max)?;
+                let id = p.id.unwrap_or(field_name);
+                let name = p.name.unwrap_or_else(|| id.clone());
+                let min = p.min.map(|e| quote!(#e)).unwrap_or(quote!(0.0));
+                let max = p.max.map(|e| quote!(#e)).unwrap_or(quote!(#implied_max));
+                let default = p.default.map(|e| quote!(#e)).unwrap_or(min.clone());
+                let unit = p.unit.unwrap_or_else(|| format_ident!("GENERIC"));
+                let dependents = &p.dependents;
+                let value_strings = &p.value_strings;
+                let with_value_strings = if value_strings.is_empty() {
+                    quote!()
+                } else {
+                    quote!(.with_value_strings(&[#(#value_strings),*]).expect("value_strings don't match the parameter range"))
+                };
+                // The variants are in scope, so both taper = Logarithmic and taper = Taper::Skew { center: 1000.0 } work
+                let with_taper = match &p.taper {
+                    Some(taper) => quote!(.with_taper({
+                        #[allow(unused_imports)]
+                        use #krate::taper::Taper::{self, *};
+                        #taper
+                    }).expect("taper doesn't fit the parameter range")),
+                    None => quote!(),
+                };
+                let flags = &p.flags;
+                let with_flags = if flags.is_empty() {
+                    quote!()
+                } else {
+                    quote!(.with_flags(#krate::algoparam::AlgoParamFlags::NONE #(| #krate::algoparam::AlgoParamFlags::#flags)*))
+                };
+                let with_unit_name = match &p.unit_name {
+                    Some(unit_name) => quote!(.with_unit_name(#unit_name).expect("null byte in unit_name")),
+                    None => quote!(),
+                };
+                children.push(quote! {
+                    {
+                        let setter_ref = this.clone();
+                        let getter_ref = this.clone();
+                        let param = #krate::algoparam::AlgoParam::new(#id, #name, #min, #max, #default,
+                            #krate::algoparam::AlgoParamUnit::#unit,
+                            ::std::boxed::Box::new(move |v| #krate::algoparam::ParamStorage::store(&setter_ref.#field_ident, v)),
+                            ::std::boxed::Box::new(move || #krate::algoparam::ParamStorage::load(&getter_ref.#field_ident)),
+                            &[#(#dependents),*])#with_value_strings #with_unit_name #with_taper #with_flags;
+                        set.add(#krate::algoparam::AlgoParamNode::Param(param)).expect("too many children in parameter set");
+                    }
+                });
+                inits.push(quote!(#field_ident: #krate::algoparam::ParamStorage::with_value(#default)));
+            },
+            FieldKind::Set(s) => {
+                let id = s.id.unwrap_or(field_name);
+                let name = s.name.unwrap_or_else(|| id.clone());
+                children.push(quote! {
+                    set.add(#krate::algoparam::AlgoParamNode::ParamSet(
+                        #krate::algoparam::Params::param_set(&this.#field_ident, #id, #name)))
+                        .expect("too many children in parameter set");
+                });
+                inits.push(quote!(#field_ident: ::std::default::Default::default()));
+            },
+            FieldKind::Plain => {
+                inits.push(quote!(#field_ident: ::std::default::Default::default()));
+            },
+        }
+    }
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #krate::algoparam::Params for #ident #ty_generics #where_clause {
+            fn param_set(this: &::std::sync::Arc<Self>, identifier: &str, name: &str) -> #krate::algoparam::AlgoParamSet {
+                let mut set = #krate::algoparam::AlgoParamSet::new(identifier, name);
+                #(#children)*
+                set
+            }
+        }
+
+        impl #impl_generics ::std::default::Default for #ident #ty_generics #where_clause {
+            fn default() -> Self {
+                #ident {
+                    #(#inits),*
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(input: DeriveInput) -> String {
+        expand(&input).err().map(|e| e.to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_literal_checks() {
+        assert_eq!(error(syn::parse_quote! {
+            struct P { #[param(min = 1.0, max = -1)] a: AtomicF32 }
+        }), "min 1 is above max -1");
+        assert_eq!(error(syn::parse_quote! {
+            struct P { #[param(min = 20.0, max = 200.0, default = 10.0)] a: AtomicF32 }
+        }), "default 10 is outside 20..200");
+        assert_eq!(error(syn::parse_quote! {
+            struct P { #[param(max = 2.0, value_strings = ["A", "B"])] a: AtomicF32 }
+        }), "2 value_strings given, the range 0..2 needs 3");
+        assert_eq!(error(syn::parse_quote! {
+            struct P { #[param(max = 10.0, taper = Logarithmic)] a: AtomicF32 }
+        }), "Logarithmic taper needs 0 < min < max, not 0..10");
+        // Expressions other than literals are left to the runtime checks
+        assert_eq!(error(syn::parse_quote! {
+            struct P { #[param(min = LOW, max = 1.0, taper = Taper::Skew { center: 2.0 })] a: AtomicF32 }
+        }), "");
+    }
+}
@@ -1,4 +1,6 @@
-use std::{ffi::{c_char, c_void, CString}, ptr::null};
+use std::{ffi::{c_char, c_void, CString}, ops::BitOr, ptr::null, sync::{atomic::Ordering, Arc}};
+
+use crate::{fault::FfiDefault, taper::Taper, util::{AtomicF32, Meter, Smooth}};
 
 
 #[derive(Debug)]
@@ -12,10 +14,63 @@ impl std::fmt::Display for OutOfRangeError {
 
 impl std::error::Error for OutOfRangeError {}
 
+// Reasons AlgoParamSet::set can refuse a write
+#[derive(Debug, PartialEq)]
+pub enum SetParameterError {
+    UnknownAddress,
+    ReadOnly,
+    OutOfRange { value: f32, min: f32, max: f32 },
+    QueueFull,
+    // The setter or a dependents hook panicked, see fault::ModuleState
+    Faulted,
+}
+
+impl std::fmt::Display for SetParameterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SetParameterError::UnknownAddress => write!(f, "No parameter at that address"),
+            SetParameterError::ReadOnly => write!(f, "Parameter is read-only"),
+            SetParameterError::OutOfRange { value, min, max } => write!(f, "Value {} is outside {}..{}", value, min, max),
+            SetParameterError::QueueFull => write!(f, "Too many parameter events are pending"),
+            SetParameterError::Faulted => write!(f, "The module has faulted"),
+        }
+    }
+}
+
+impl std::error::Error for SetParameterError {}
+
+// Reasons an AlgoParam can't be built
+#[derive(Debug, PartialEq)]
+pub enum AlgoParamError {
+    NullByte(String),
+    InvalidRange { min: f32, max: f32 },
+    DefaultOutOfRange { default: f32, min: f32, max: f32 },
+    Unbound,
+    ValueStringCount { expected: usize, found: usize },
+    InvalidTaper(Taper),
+}
+
+impl std::fmt::Display for AlgoParamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AlgoParamError::NullByte(s) => write!(f, "String contains a null byte: {:?}", s),
+            AlgoParamError::InvalidRange { min, max } => write!(f, "Invalid range {}..{}", min, max),
+            AlgoParamError::DefaultOutOfRange { default, min, max } => write!(f, "Default {} is outside {}..{}", default, min, max),
+            AlgoParamError::Unbound => write!(f, "Parameter has no setter/getter"),
+            AlgoParamError::ValueStringCount { expected, found } => write!(f, "Expected {} value strings for the integer range, got {}", expected, found),
+            AlgoParamError::InvalidTaper(taper) => write!(f, "Taper {:?} doesn't fit the parameter range", taper),
+        }
+    }
+}
+
+impl std::error::Error for AlgoParamError {}
+
 
 
 pub const KEY_NOT_FOUND: u64 = 0xffff_ffff_ffff_ffff;
 pub const KEY_MASK: u64  = 0x00ff_0000_0000_0000;
+// Dependents of a single parameter that set_reporting checks for changes
+pub const MAX_REPORTED_DEPENDENTS: usize = 32;
 
 #[repr(i32)]
 #[derive(Debug, Clone, Copy)]
@@ -52,11 +107,51 @@ pub enum AlgoParamUnit {
 
 
 
+#[derive(Clone)]
 pub enum AlgoParamNode {
     Param(AlgoParam),
     ParamSet(AlgoParamSet),
 }
 
+// What AlgoParamSet::set does with values outside min..max. NaN is replaced by the default when clamping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RangePolicy {
+    #[default]
+    Clamp,
+    Reject,
+}
+
+// Hints for hosts and UIs, exported as AlgoCParam.flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AlgoParamFlags(pub u32);
+
+impl AlgoParamFlags {
+    pub const NONE: AlgoParamFlags = AlgoParamFlags(0);
+    pub const READ_ONLY: AlgoParamFlags = AlgoParamFlags(1 << 0);       // output of the algorithm, set refuses writes
+    pub const HIDDEN: AlgoParamFlags = AlgoParamFlags(1 << 1);          // internal, not shown in generic UIs
+    pub const NON_AUTOMATABLE: AlgoParamFlags = AlgoParamFlags(1 << 2); // settings like the oversampling factor
+    pub const METER: AlgoParamFlags = AlgoParamFlags(1 << 3);           // level or gain reduction display
+    pub const GLOBAL: AlgoParamFlags = AlgoParamFlags(1 << 4);          // changes other parameters, e.g. a preset or mode switch
+    pub const RAMPABLE: AlgoParamFlags = AlgoParamFlags(1 << 5);        // the algorithm smooths changes itself
+
+    pub fn contains(&self, other: AlgoParamFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for AlgoParamFlags {
+    type Output = AlgoParamFlags;
+
+    fn bitor(self, rhs: AlgoParamFlags) -> AlgoParamFlags {
+        AlgoParamFlags(self.0 | rhs.0)
+    }
+}
+
+pub type ValueFormatter = Arc<dyn Fn(f32)->String + Send + Sync>;
+pub type ValueParser = Arc<dyn Fn(&str)->Option<f32> + Send + Sync>;
+// Called with the applied value after the setter, to recompute the storage of the dependents
+pub type DependentsHook = Arc<dyn Fn(f32) + Send + Sync>;
+
 pub struct AlgoParam {
     pub identifier: CString,
     pub name: CString,
@@ -64,25 +159,59 @@ pub struct AlgoParam {
     pub max: f32,
     pub default: f32,
     pub unit: AlgoParamUnit,
-    pub setter: Box<dyn Fn(f32)->()>,
-    pub getter: Box<dyn Fn()->f32>,
-    pub dependents: Vec<CString>,                         // logical names
+    pub unit_name: Option<CString>,                       // label for CUSTOMUNIT, e.g. "voices"
+    pub taper: Taper,                                     // mapping to normalized 0..1
+    pub flags: AlgoParamFlags,
+    pub setter: Arc<dyn Fn(f32) + Send + Sync>,           // called on the control thread, see the SoundModule thread contract
+    pub getter: Arc<dyn Fn()->f32 + Send + Sync>,         // shared with copies of the parameter, see AlgoParamSet::replace_subtree
+    pub dependents: Vec<CString>,                         // logical names, keypaths relative to the set holding this parameter
+    pub update_dependents: Option<DependentsHook>,
     pub dependents_ptr: Option<Box<[*const c_char]>>,     // raw array for FFI - content owned by the logical names above.
+    pub value_strings: Vec<CString>,                      // one label per integer value in min..=max (INDEXED)
+    pub value_strings_ptr: Option<Box<[*const c_char]>>,  // raw array for FFI - content owned by the labels above.
+    pub formatter: Option<ValueFormatter>,                // overrides the unit formatting
+    pub parser: Option<ValueParser>,                      // overrides the unit parsing
+}
+
+// The raw pointers only refer to the CStrings owned by the parameter itself, which are never mutated
+unsafe impl Send for AlgoParam {}
+unsafe impl Sync for AlgoParam {}
+
+// Copies share the setter, getter and hooks, and so the storage
+impl Clone for AlgoParam {
+    fn clone(&self) -> AlgoParam {
+        let dependents = self.dependents.clone();
+        let value_strings = self.value_strings.clone();
+        AlgoParam {
+            identifier: self.identifier.clone(),
+            name: self.name.clone(),
+            min: self.min,
+            max: self.max,
+            default: self.default,
+            unit: self.unit,
+            unit_name: self.unit_name.clone(),
+            taper: self.taper,
+            flags: self.flags,
+            setter: self.setter.clone(),
+            getter: self.getter.clone(),
+            dependents_ptr: raw_string_array(&dependents),
+            dependents,
+            update_dependents: self.update_dependents.clone(),
+            value_strings_ptr: raw_string_array(&value_strings),
+            value_strings,
+            formatter: self.formatter.clone(),
+            parser: self.parser.clone(),
+        }
+    }
 }
 
 impl AlgoParam {
     pub fn new(key: &str, name: &str, min: f32, max: f32, default:  f32, unit: AlgoParamUnit, 
-                setter: Box<dyn Fn(f32)->()>, getter: Box<dyn Fn()->f32>, dependents: &[&str]) -> AlgoParam{
+                setter: Box<dyn Fn(f32)->() + Send + Sync>, getter: Box<dyn Fn()->f32 + Send + Sync>, dependents: &[&str]) -> AlgoParam{
         let _key = CString::new(key).expect("Should not fail ...");
         let _name = CString::new(name).expect("Should not fail ...");
         let _dependents: Vec<CString> = dependents.iter().map(|s| CString::new(*s).expect("null byte in dependent name")).collect();
-        let _dependents_ptr = if _dependents.is_empty() {
-            None
-        } else {
-            let mut raw_ptrs: Vec<*const c_char> = _dependents.iter().map(|s| s.as_ptr()).collect();
-            raw_ptrs.push(null());
-            Some(raw_ptrs.into_boxed_slice())
-        };
+        let _dependents_ptr = raw_string_array(&_dependents);
 
         AlgoParam {
             identifier: _key, 
@@ -91,22 +220,372 @@ impl AlgoParam {
             max, 
             default,
             unit, 
-            setter, 
-            getter, 
+            unit_name: None,
+            taper: Taper::Linear,
+            flags: AlgoParamFlags::NONE,
+            setter: Arc::from(setter), 
+            getter: Arc::from(getter), 
             dependents: _dependents, 
-            dependents_ptr: _dependents_ptr
+            update_dependents: None,
+            dependents_ptr: _dependents_ptr,
+            value_strings: Vec::new(),
+            value_strings_ptr: None,
+            formatter: None,
+            parser: None,
+        }
+    }
+
+    // Makes this a CUSTOMUNIT parameter labelled unit_name
+    pub fn with_unit_name(mut self, unit_name: &str) -> Result<AlgoParam, AlgoParamError> {
+        self.unit_name = Some(to_cstring(unit_name)?);
+        self.unit = AlgoParamUnit::CUSTOMUNIT;
+        Ok(self)
+    }
+
+    pub fn with_taper(mut self, taper: Taper) -> Result<AlgoParam, AlgoParamError> {
+        if !taper.is_valid_for(self.min, self.max) {
+            return Err(AlgoParamError::InvalidTaper(taper));
+        }
+        self.taper = taper;
+        Ok(self)
+    }
+
+    pub fn with_flags(mut self, flags: AlgoParamFlags) -> AlgoParam {
+        self.flags = flags;
+        self
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.flags.contains(AlgoParamFlags::READ_ONLY)
+    }
+
+    // The value set would pass to the setter: NaN handled and out of range values treated according to
+    // policy, INDEXED and BOOLEAN values rounded to integers. Uses max/min rather than clamp, which panics
+    // if min and max were changed to an inverted range after construction.
+    pub fn effective_value(&self, value: f32, policy: RangePolicy) -> Result<f32, SetParameterError> {
+        let value = match policy {
+            RangePolicy::Clamp if value.is_nan() => self.default,
+            RangePolicy::Clamp => value.max(self.min).min(self.max),
+            RangePolicy::Reject if (self.min..=self.max).contains(&value) => value,
+            RangePolicy::Reject => return Err(SetParameterError::OutOfRange { value, min: self.min, max: self.max }),
+        };
+        match self.unit {
+            AlgoParamUnit::INDEXED | AlgoParamUnit::BOOLEAN => Ok(value.round().max(self.min).min(self.max)),
+            _ => Ok(value),
+        }
+    }
+
+    pub fn to_normalized(&self, value: f32) -> f32 {
+        self.taper.to_normalized(value, self.min, self.max)
+    }
+
+    pub fn from_normalized(&self, normalized: f32) -> f32 {
+        self.taper.from_normalized(normalized, self.min, self.max)
+    }
+
+    pub fn unit_name_as_raw(&self) -> *const c_char {
+        self.unit_name.as_ref().map(|s| s.as_ptr()).unwrap_or(null())
+    }
+
+    pub fn with_dependents_hook(mut self, hook: impl Fn(f32) + Send + Sync + 'static) -> AlgoParam {
+        self.update_dependents = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn with_formatter(mut self, formatter: impl Fn(f32) -> String + Send + Sync + 'static) -> AlgoParam {
+        self.formatter = Some(Arc::new(formatter));
+        self
+    }
+
+    pub fn with_parser(mut self, parser: impl Fn(&str) -> Option<f32> + Send + Sync + 'static) -> AlgoParam {
+        self.parser = Some(Arc::new(parser));
+        self
+    }
+
+    // Display string for value: the formatter override, the value string or the unit formatting, in that order
+    pub fn format_value(&self, value: f32) -> String {
+        if let Some(formatter) = &self.formatter {
+            return formatter(value);
+        }
+        if let Some(label) = self.value_string(value) {
+            return label.to_string_lossy().into_owned();
+        }
+        match &self.unit_name {
+            Some(unit_name) => format!("{} {}", self.unit.format(value), unit_name.to_string_lossy()),
+            None => self.unit.format(value),
+        }
+    }
+
+    // Inverse of format_value. Returns None if the text can't be understood.
+    pub fn parse_value(&self, text: &str) -> Option<f32> {
+        if let Some(parser) = &self.parser {
+            return parser(text);
+        }
+        let trimmed = text.trim();
+        if let Some(idx) = self.value_strings.iter().position(|s| s.to_bytes().eq_ignore_ascii_case(trimmed.as_bytes())) {
+            return Some(self.min + idx as f32);
+        }
+        let unit_name = self.unit_name.as_ref().map(|s| s.to_string_lossy()).unwrap_or_default();
+        let number = trimmed.strip_suffix(unit_name.as_ref()).unwrap_or(trimmed);
+        self.unit.parse(number)
+    }
+
+    fn value_string(&self, value: f32) -> Option<&CString> {
+        let idx = (value - self.min).round();
+        if idx < 0.0 {
+            None
+        } else {
+            self.value_strings.get(idx as usize)
+        }
+    }
+
+    // Attaches one label per integer value in min..=max, shown by hosts instead of a slider
+    pub fn with_value_strings(mut self, value_strings: &[&str]) -> Result<AlgoParam, AlgoParamError> {
+        let expected = integer_range_len(self.min, self.max);
+        if expected != Some(value_strings.len()) {
+            return Err(AlgoParamError::ValueStringCount { expected: expected.unwrap_or(0), found: value_strings.len() });
+        }
+        self.value_strings = value_strings.iter().map(|s| to_cstring(s)).collect::<Result<Vec<_>,_>>()?;
+        self.value_strings_ptr = raw_string_array(&self.value_strings);
+        Ok(self)
+    }
+
+    // Parameter whose setter and getter store into and load from storage
+    pub fn bound<S: ParamStorage>(key: &str, name: &str, min: f32, max: f32, default: f32, unit: AlgoParamUnit,
+                storage: &Arc<S>) -> AlgoParam {
+        AlgoParam::bound_with(key, name, min, max, default, unit, storage, ValueTransform::IDENTITY)
+    }
+
+    // As bound, but the stored value is transform.to_storage of the parameter value (e.g. dB stored as linear gain)
+    #[allow(clippy::too_many_arguments)]
+    pub fn bound_with<S: ParamStorage>(key: &str, name: &str, min: f32, max: f32, default: f32, unit: AlgoParamUnit,
+                storage: &Arc<S>, transform: ValueTransform) -> AlgoParam {
+        let (setter, getter) = storage_accessors(storage, transform);
+        AlgoParam::new(key, name, min, max, default, unit, setter, getter, &[])
+    }
+
+    pub fn builder(key: &str) -> AlgoParamBuilder {
+        AlgoParamBuilder {
+            key: key.to_string(),
+            name: None,
+            min: 0.0,
+            max: 1.0,
+            default: None,
+            unit: AlgoParamUnit::GENERIC,
+            unit_name: None,
+            taper: Taper::Linear,
+            flags: AlgoParamFlags::NONE,
+            setter: None,
+            getter: None,
+            dependents: Vec::new(),
+            update_dependents: None,
+            value_strings: Vec::new(),
+            formatter: None,
+            parser: None,
         }
     }
 
     pub fn dependents_as_raw(&self) -> *const *const c_char {
         self.dependents_ptr.as_ref().map(|slice| slice.as_ptr()).unwrap_or(null())
     }
+
+    pub fn value_strings_as_raw(&self) -> *const *const c_char {
+        self.value_strings_ptr.as_ref().map(|slice| slice.as_ptr()).unwrap_or(null())
+    }
+}
+
+// Number of integers in min..=max, None if the bounds aren't integers
+fn integer_range_len(min: f32, max: f32) -> Option<usize> {
+    if min.fract() != 0.0 || max.fract() != 0.0 || min > max {
+        None
+    } else {
+        Some((max - min) as usize + 1)
+    }
+}
+
+fn to_cstring(s: &str) -> Result<CString, AlgoParamError> {
+    CString::new(s).map_err(|_| AlgoParamError::NullByte(s.to_string()))
+}
+
+// Fluent alternative to AlgoParam::new that validates instead of panicking:
+// AlgoParam::builder("cutoff").name("Cutoff").range(20.0, 20000.0).default(1000.0).unit(AlgoParamUnit::HERTZ).bind(&cutoff).build()
+// The name defaults to the key, the range to 0..1 and the default to min.
+pub struct AlgoParamBuilder {
+    key: String,
+    name: Option<String>,
+    min: f32,
+    max: f32,
+    default: Option<f32>,
+    unit: AlgoParamUnit,
+    unit_name: Option<String>,
+    taper: Taper,
+    flags: AlgoParamFlags,
+    setter: Option<Box<dyn Fn(f32) + Send + Sync>>,
+    getter: Option<Box<dyn Fn()->f32 + Send + Sync>>,
+    dependents: Vec<String>,
+    update_dependents: Option<DependentsHook>,
+    value_strings: Vec<String>,
+    formatter: Option<ValueFormatter>,
+    parser: Option<ValueParser>,
+}
+
+impl AlgoParamBuilder {
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn range(mut self, min: f32, max: f32) -> Self {
+        self.min = min;
+        self.max = max;
+        self
+    }
+
+    pub fn default(mut self, default: f32) -> Self {
+        self.default = Some(default);
+        self
+    }
+
+    pub fn unit(mut self, unit: AlgoParamUnit) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    // Sets the unit to CUSTOMUNIT with the given label
+    pub fn custom_unit(mut self, unit_name: &str) -> Self {
+        self.unit = AlgoParamUnit::CUSTOMUNIT;
+        self.unit_name = Some(unit_name.to_string());
+        self
+    }
+
+    pub fn taper(mut self, taper: Taper) -> Self {
+        self.taper = taper;
+        self
+    }
+
+    pub fn flags(mut self, flags: AlgoParamFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn setter(mut self, setter: impl Fn(f32) + Send + Sync + 'static) -> Self {
+        self.setter = Some(Box::new(setter));
+        self
+    }
+
+    pub fn getter(mut self, getter: impl Fn() -> f32 + Send + Sync + 'static) -> Self {
+        self.getter = Some(Box::new(getter));
+        self
+    }
+
+    // Uses storage for both setter and getter
+    pub fn bind<S: ParamStorage>(self, storage: &Arc<S>) -> Self {
+        self.bind_with(storage, ValueTransform::IDENTITY)
+    }
+
+    pub fn bind_with<S: ParamStorage>(mut self, storage: &Arc<S>, transform: ValueTransform) -> Self {
+        let (setter, getter) = storage_accessors(storage, transform);
+        self.setter = Some(setter);
+        self.getter = Some(getter);
+        self
+    }
+
+    // Read-only METER parameter showing the meter level in dB, -96..0 unless a range has been given
+    pub fn meter(mut self, meter: &Arc<Meter>) -> Self {
+        if (self.min, self.max) == (0.0, 1.0) {
+            (self.min, self.max) = (-96.0, 0.0);
+        }
+        self.unit = AlgoParamUnit::DECIBELS;
+        self.flags = self.flags | AlgoParamFlags::READ_ONLY | AlgoParamFlags::METER;
+        self.bind_with(meter, ValueTransform::DB_TO_GAIN)
+    }
+
+    pub fn dependents(mut self, dependents: &[&str]) -> Self {
+        self.dependents = dependents.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    // See AlgoParamSet::set_reporting
+    pub fn update_dependents(mut self, hook: impl Fn(f32) + Send + Sync + 'static) -> Self {
+        self.update_dependents = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn formatter(mut self, formatter: impl Fn(f32) -> String + Send + Sync + 'static) -> Self {
+        self.formatter = Some(Arc::new(formatter));
+        self
+    }
+
+    pub fn parser(mut self, parser: impl Fn(&str) -> Option<f32> + Send + Sync + 'static) -> Self {
+        self.parser = Some(Arc::new(parser));
+        self
+    }
+
+    // Implies a range of 0..count-1 unless a range has been given
+    pub fn value_strings(mut self, value_strings: &[&str]) -> Self {
+        self.value_strings = value_strings.iter().map(|s| s.to_string()).collect();
+        if (self.min, self.max) == (0.0, 1.0) && !value_strings.is_empty() {
+            self.max = (value_strings.len() - 1) as f32;
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<AlgoParam, AlgoParamError> {
+        let (min, max) = (self.min, self.max);
+        if !min.is_finite() || !max.is_finite() || min > max {
+            return Err(AlgoParamError::InvalidRange { min, max });
+        }
+        let default = self.default.unwrap_or(min);
+        if !(min..=max).contains(&default) {
+            return Err(AlgoParamError::DefaultOutOfRange { default, min, max });
+        }
+        if !self.taper.is_valid_for(min, max) {
+            return Err(AlgoParamError::InvalidTaper(self.taper));
+        }
+        let (Some(setter), Some(mut getter)) = (self.setter, self.getter) else {
+            return Err(AlgoParamError::Unbound);
+        };
+        if self.flags.contains(AlgoParamFlags::METER) {
+            // Meters go below -96 dB in silence and above 0 dB when clipping, report them inside the range
+            let level = getter;
+            getter = Box::new(move || level().max(min).min(max));
+        }
+        let identifier = to_cstring(&self.key)?;
+        let name = match &self.name {
+            Some(name) => to_cstring(name)?,
+            None => identifier.clone(),
+        };
+        let dependents = self.dependents.iter().map(|s| to_cstring(s)).collect::<Result<Vec<_>,_>>()?;
+        let dependents_ptr = raw_string_array(&dependents);
+        let unit_name = self.unit_name.as_deref().map(to_cstring).transpose()?;
+
+        let param = AlgoParam { identifier, name, min, max, default, unit: self.unit, unit_name, taper: self.taper, flags: self.flags, setter: Arc::from(setter), getter: Arc::from(getter), dependents, update_dependents: self.update_dependents, dependents_ptr,
+            value_strings: Vec::new(), value_strings_ptr: None, formatter: self.formatter, parser: self.parser };
+        if self.value_strings.is_empty() {
+            Ok(param)
+        } else {
+            param.with_value_strings(&self.value_strings.iter().map(String::as_str).collect::<Vec<_>>())
+        }
+    }
 }
 
+// Null terminated array of pointers into strings, or None if strings is empty
+fn raw_string_array(strings: &[CString]) -> Option<Box<[*const c_char]>> {
+    if strings.is_empty() {
+        None
+    } else {
+        let mut raw_ptrs: Vec<*const c_char> = strings.iter().map(|s| s.as_ptr()).collect();
+        raw_ptrs.push(null());
+        Some(raw_ptrs.into_boxed_slice())
+    }
+}
+
+#[derive(Clone)]
 pub struct AlgoParamSet {
     pub identifier: CString,
     pub name: CString,
     pub children: Vec<AlgoParamNode>,
+    pub range_policy: RangePolicy,    // used by set on this set, including for parameters in subsets
 }
 
 impl AlgoParamSet {
@@ -114,7 +593,7 @@ impl AlgoParamSet {
         let children = Vec::<AlgoParamNode>::new();
         let _identifier = CString::new(identifier).expect("Should not have failed here");
         let _name = CString::new(name).expect("Should not have failed here...");
-        AlgoParamSet { identifier: _identifier, name: _name, children }
+        AlgoParamSet { identifier: _identifier, name: _name, children, range_policy: RangePolicy::Clamp }
     }
 
     pub fn add(&mut self, child: AlgoParamNode) -> Result<(),OutOfRangeError> {
@@ -141,6 +620,82 @@ impl AlgoParamSet {
         }
     }
 
+    pub fn get_param(&self, key: u64) -> Option<&AlgoParam> {
+        let idx = key >> 56;
+        if idx >= self.children.len() as u64 {
+            None
+        } else {
+            match &self.children[idx as usize] {
+                AlgoParamNode::Param(param) => Some(param),
+                AlgoParamNode::ParamSet(set) => set.get_param(key<<8),
+            }
+        }
+    }
+
+    // Resolves a dot separated keypath relative to this set (e.g. "subset1.param1_1") to a parameter address
+    pub fn address_of(&self, keypath: &str) -> Option<u64> {
+        self.address_of_path(keypath.split('.'))
+    }
+
+    // address_of for a keypath given as its elements, so callers on the audio thread can resolve a
+    // keypath made of several parts without joining them
+    pub(crate) fn address_of_path<'a>(&self, elems: impl Iterator<Item = &'a str>) -> Option<u64> {
+        let mut set = self;
+        let mut address = KEY_NOT_FOUND;
+        let mut elems = elems.peekable();
+        let mut shift = 56;
+        while let Some(elem) = elems.next() {
+            let last = elems.peek().is_none();
+            let idx = set.children.iter().position(|child| match child {
+                AlgoParamNode::Param(param) => last && param.identifier.as_bytes() == elem.as_bytes(),
+                AlgoParamNode::ParamSet(sub) => !last && sub.identifier.as_bytes() == elem.as_bytes(),
+            })?;
+            address &= !(0xffu64 << shift);
+            address |= (idx as u64) << shift;
+            if let AlgoParamNode::ParamSet(sub) = &set.children[idx] {
+                if shift == 0 {
+                    return None;
+                }
+                set = sub;
+                shift -= 8;
+            }
+        }
+        Some(address)
+    }
+
+    // Replaces the set at the dot separated keypath relative to this set with set, which takes over the
+    // identifier of the replaced one. Returns the address of the set: its index bytes followed by 0xff,
+    // like the basekeys of find_first_set.
+    pub fn replace_subtree(&mut self, keypath: &str, mut set: AlgoParamSet) -> Result<u64, OutOfRangeError> {
+        let mut parent = self;
+        let mut address = KEY_NOT_FOUND;
+        let mut shift = 56;
+        let mut elems = keypath.split('.').peekable();
+        while let Some(elem) = elems.next() {
+            let idx = parent.children.iter().position(|child|
+                matches!(child, AlgoParamNode::ParamSet(sub) if sub.identifier.as_bytes() == elem.as_bytes())
+            ).ok_or(OutOfRangeError)?;
+            address &= !(0xffu64 << shift);
+            address |= (idx as u64) << shift;
+            let AlgoParamNode::ParamSet(sub) = &mut parent.children[idx] else { unreachable!() };
+            if elems.peek().is_none() {
+                set.identifier = sub.identifier.clone();
+                *sub = set;
+                return Ok(address);
+            }
+            if shift == 0 {
+                break;
+            }
+            parent = sub;
+            shift -= 8;
+        }
+        Err(OutOfRangeError)
+    }
+
+    pub fn find_param(&self, keypath: &str) -> Option<&AlgoParam> {
+        self.get_param(self.address_of(keypath)?)
+    }
+
     pub fn find_first_set(&self, basekey: u64) -> Option<(&AlgoParamSet,u64)> {
         if basekey == KEY_NOT_FOUND {
             // Just find the first one and return that if it exists
@@ -243,24 +798,199 @@ impl AlgoParamSet {
         }
     }
 
-    pub fn set(&mut self, value: f32, key: u64) -> Result<(),OutOfRangeError> {
-        if let Some(param) = self.get_param_mut(key) {
-            let val = (param.setter)(value);
-            return Ok(val);
-        } 
-        Err(OutOfRangeError)
+    pub fn with_range_policy(mut self, range_policy: RangePolicy) -> AlgoParamSet {
+        self.range_policy = range_policy;
+        self
+    }
+
+    // Calls every setter in the tree with its declared default, skipping read-only parameters.
+    // Returns the number of parameters reset.
+    pub fn reset_to_defaults(&self) -> usize {
+        self.children.iter().map(|child| match child {
+            AlgoParamNode::Param(param) => reset_param(param),
+            AlgoParamNode::ParamSet(set) => set.reset_to_defaults(),
+        }).sum()
+    }
+
+    // Like reset_to_defaults for the parameter or set at key. KEY_NOT_FOUND means this set.
+    pub fn reset_to_defaults_at(&self, key: u64) -> Result<usize, OutOfRangeError> {
+        if key == KEY_NOT_FOUND {
+            return Ok(self.reset_to_defaults());
+        }
+        let tail = (key << 8) | 0xff;
+        match self.children.get((key >> 56) as usize).ok_or(OutOfRangeError)? {
+            AlgoParamNode::Param(param) if tail == KEY_NOT_FOUND => Ok(reset_param(param)),
+            AlgoParamNode::Param(_) => Err(OutOfRangeError),
+            AlgoParamNode::ParamSet(set) => set.reset_to_defaults_at(tail),
+        }
+    }
+
+    // Returns the value actually passed to the setter, see AlgoParam::effective_value
+    pub fn set(&self, value: f32, key: u64) -> Result<f32,SetParameterError> {
+        self.set_reporting(value, key, &mut |_, _| {})
+    }
+
+    // Like set, and when the parameter has a dependents hook, runs it and calls report with the address and
+    // new value of each dependent whose value changed
+    pub fn set_reporting(&self, value: f32, key: u64, report: &mut dyn FnMut(u64, f32)) -> Result<f32,SetParameterError> {
+        let (set, param, depth) = self.locate(key, 0).ok_or(SetParameterError::UnknownAddress)?;
+        if param.is_read_only() {
+            return Err(SetParameterError::ReadOnly);
+        }
+        let value = param.effective_value(value, self.range_policy)?;
+        (param.setter)(value);
+        if let Some(hook) = &param.update_dependents {
+            // Dependent keypaths are relative to set, which sits depth bytes down the address.
+            // A fixed array, as scheduled events get here on the audio thread.
+            let prefix = key & !(u64::MAX >> (8 * depth));
+            let mut before = [(KEY_NOT_FOUND, 0.0f32); MAX_REPORTED_DEPENDENTS];
+            let mut count = 0;
+            let addresses = param.dependents.iter()
+                .filter_map(|name| set.address_of(name.to_str().ok()?))
+                .map(|relative| prefix | (relative >> (8 * depth)));
+            for address in addresses.take(MAX_REPORTED_DEPENDENTS) {
+                if let Ok(old) = self.get(address) {
+                    before[count] = (address, old);
+                    count += 1;
+                }
+            }
+            hook(value);
+            for &(address, old) in &before[..count] {
+                if let Ok(new) = self.get(address) && new.to_bits() != old.to_bits() {
+                    report(address, new);
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    // The set directly holding the parameter at key, and how many address bytes lead there from self
+    fn locate(&self, key: u64, depth: u32) -> Option<(&AlgoParamSet, &AlgoParam, u32)> {
+        match self.children.get((key >> 56) as usize)? {
+            AlgoParamNode::Param(param) => Some((self, param, depth)),
+            AlgoParamNode::ParamSet(set) => set.locate(key << 8, depth + 1),
+        }
     }
 
-    pub fn get(&mut self, key: u64) -> Result<f32, OutOfRangeError> {
-        if let Some(param) = self.get_param_mut(key) {
+    pub fn get(&self, key: u64) -> Result<f32, OutOfRangeError> {
+        if let Some(param) = self.get_param(key) {
             let val = (param.getter)();
             return Ok(val);
         } 
         Err(OutOfRangeError)
     }
+
+    // Reads keys[i] into values[i] (0 for unknown keys), e.g. to poll all meters at once.
+    // Returns the number of keys found.
+    pub fn get_many(&self, keys: &[u64], values: &mut [f32]) -> usize {
+        let mut found = 0;
+        for (key, value) in keys.iter().zip(values.iter_mut()) {
+            *value = match self.get(*key) {
+                Ok(v) => { found += 1; v },
+                Err(_) => 0.0,
+            };
+        }
+        found
+    }
 }
 
 
+fn reset_param(param: &AlgoParam) -> usize {
+    if param.is_read_only() {
+        return 0;
+    }
+    (param.setter)(param.default);
+    1
+}
+
+// Storage cell for a single parameter value, shared between a setter/getter pair and the algorithm
+pub trait ParamStorage : Send + Sync + 'static {
+    fn with_value(value: f32) -> Self;
+    fn store(&self, value: f32);
+    fn load(&self) -> f32;
+}
+
+impl ParamStorage for AtomicF32 {
+    fn with_value(value: f32) -> Self {
+        AtomicF32::new(value)
+    }
+
+    fn store(&self, value: f32) {
+        AtomicF32::store(self, value, Ordering::Relaxed)
+    }
+
+    fn load(&self) -> f32 {
+        AtomicF32::load(self, Ordering::Relaxed)
+    }
+}
+
+// Meters are written by the audio thread, writes through the parameter are ignored
+impl ParamStorage for Meter {
+    fn with_value(value: f32) -> Self {
+        let meter = Meter::default();
+        meter.update(value, 0.0);
+        meter
+    }
+
+    fn store(&self, _value: f32) {}
+
+    fn load(&self) -> f32 {
+        self.level()
+    }
+}
+
+impl ParamStorage for Smooth {
+    fn with_value(value: f32) -> Self {
+        Smooth::new_with_value(value)
+    }
+
+    // Sets the target, the audio thread ramps towards it
+    fn store(&self, value: f32) {
+        self.set(value)
+    }
+
+    fn load(&self) -> f32 {
+        self.get()
+    }
+}
+
+// Mapping between the value a parameter presents to the host and the value kept in its storage
+#[derive(Clone, Copy)]
+pub struct ValueTransform {
+    pub to_storage: fn(f32) -> f32,
+    pub from_storage: fn(f32) -> f32,
+}
+
+impl ValueTransform {
+    pub const IDENTITY: ValueTransform = ValueTransform { to_storage: |v| v, from_storage: |v| v };
+    // Decibels presented, linear gain stored
+    pub const DB_TO_GAIN: ValueTransform = ValueTransform {
+        to_storage: |db| 10.0f32.powf(db / 20.0),
+        from_storage: |gain| 20.0 * gain.max(1e-10).log10(),
+    };
+    // Percent presented, 0..1 fraction stored
+    pub const PERCENT_TO_FRACTION: ValueTransform = ValueTransform { to_storage: |p| p / 100.0, from_storage: |f| f * 100.0 };
+}
+
+type Accessors = (Box<dyn Fn(f32) + Send + Sync>, Box<dyn Fn() -> f32 + Send + Sync>);
+
+fn storage_accessors<S: ParamStorage>(storage: &Arc<S>, transform: ValueTransform) -> Accessors {
+    let (s, g) = (storage.clone(), storage.clone());
+    (Box::new(move |v| s.store((transform.to_storage)(v))), Box::new(move || (transform.from_storage)(g.load())))
+}
+
+// Parameter storage that builds its own AlgoParamSet, usually generated with #[derive(Params)].
+// The setters and getters in the set hold on to the Arc.
+pub trait Params : Send + Sync + 'static {
+    fn param_set(this: &Arc<Self>, identifier: &str, name: &str) -> AlgoParamSet;
+}
+
+// Creates default storage and its parameter set, ready to be returned from Algorithm::get_parameters
+pub fn new_params<P: Params + Default>(identifier: &str, name: &str) -> (AlgoParamSet, Arc<P>) {
+    let params = Arc::new(P::default());
+    (P::param_set(&params, identifier, name), params)
+}
+
 /// C interface. Note that the API does not offer any way to actually get a tree - that needs to be supplied by
 /// a client module. It is expected that tree is of the type *const AlgoParamSet
 
@@ -273,6 +1003,11 @@ pub struct AlgoCParam {
     pub default: f32,
     pub dtype: i32,
     pub dependents: *const *const c_char,
+    pub value_strings: *const *const c_char,
+    pub unit_name: *const c_char,
+    pub taper: i32,
+    pub taper_param: f32,
+    pub flags: u32,
 }
 
 #[repr(C)]
@@ -290,7 +1025,12 @@ impl AlgoCParam {
             max: 0.0,
             default: 0.0,
             dtype: 0,
-            dependents: null()
+            dependents: null(),
+            value_strings: null(),
+            unit_name: null(),
+            taper: 0,
+            taper_param: 0.0,
+            flags: 0,
         }
     }
 
@@ -303,6 +1043,11 @@ impl AlgoCParam {
             default: from.default,
             dtype: from.unit as i32,
             dependents: from.dependents_as_raw(),
+            value_strings: from.value_strings_as_raw(),
+            unit_name: from.unit_name_as_raw(),
+            taper: from.taper.kind() as i32,
+            taper_param: from.taper.parameter(),
+            flags: from.flags.0,
         }
     }
 }
@@ -317,8 +1062,18 @@ impl AlgoCParamSet {
     }
 }
 
+impl FfiDefault for AlgoCParam {
+    fn ffi_default() -> Self { AlgoCParam::null() }
+}
+
+impl FfiDefault for AlgoCParamSet {
+    fn ffi_default() -> Self { AlgoCParamSet::null() }
+}
+
 // API functions without name mangling
-pub fn algoparam_get_first_set(tree: *const c_void, basekey: *mut u64) -> AlgoCParamSet {
+/// # Safety
+/// tree must be a tree from soundmodule_get_params that is still valid, basekey must be writable.
+pub unsafe fn algoparam_get_first_set(tree: *const c_void, basekey: *mut u64) -> AlgoCParamSet {
     let set = unsafe { &*(tree as *const AlgoParamSet)};
     let bkey = unsafe { *basekey };
     if let Some(newkey) = set.find_first_set(bkey) {
@@ -330,7 +1085,9 @@ pub fn algoparam_get_first_set(tree: *const c_void, basekey: *mut u64) -> AlgoCP
     }
 }
 
-pub fn algoparam_get_next_set(tree: *const c_void, basekey: *mut u64) -> AlgoCParamSet {
+/// # Safety
+/// tree must be a tree from soundmodule_get_params that is still valid, basekey must be writable.
+pub unsafe fn algoparam_get_next_set(tree: *const c_void, basekey: *mut u64) -> AlgoCParamSet {
     let set = unsafe { &*(tree as *const AlgoParamSet)};
     let bkey = unsafe { *basekey };
     if let Some(newkey) = set.find_next_set(bkey) {
@@ -342,7 +1099,9 @@ pub fn algoparam_get_next_set(tree: *const c_void, basekey: *mut u64) -> AlgoCPa
     }
 }
 
-pub fn algoparam_get_first_param(tree: *const c_void, basekey: *mut u64) -> AlgoCParam {
+/// # Safety
+/// tree must be a tree from soundmodule_get_params that is still valid, basekey must be writable.
+pub unsafe fn algoparam_get_first_param(tree: *const c_void, basekey: *mut u64) -> AlgoCParam {
     let set = unsafe { &*(tree as *const AlgoParamSet)};
     let bkey = unsafe { *basekey };
     if let Some(newkey) = set.find_first_param(bkey) {
@@ -355,7 +1114,9 @@ pub fn algoparam_get_first_param(tree: *const c_void, basekey: *mut u64) -> Algo
 
 }
 
-pub fn algoparam_get_next_param(tree: *const c_void, basekey: *mut u64) -> AlgoCParam {
+/// # Safety
+/// tree must be a tree from soundmodule_get_params that is still valid, basekey must be writable.
+pub unsafe fn algoparam_get_next_param(tree: *const c_void, basekey: *mut u64) -> AlgoCParam {
     let set = unsafe { &*(tree as *const AlgoParamSet)};
     let bkey = unsafe { *basekey };
     if let Some(newkey) = set.find_next_param(bkey) {
@@ -416,82 +1177,319 @@ mod tests {
         let tree = build_tree();
         let mut basekey = KEY_NOT_FOUND;
         // Test first subset retrieval
-        let first_subset = algoparam_get_first_set(as_voidptr(&tree), &mut basekey);
+        let first_subset = unsafe { algoparam_get_first_set(as_voidptr(&tree), &mut basekey) };
         assert_eq!(as_strref(first_subset.key), "subset1");
         let expected = (0x00u64 << 56) | 0x00ff_ffff_ffff_ffff;
         assert_eq!(basekey, expected);
         let subset1 = basekey;
 
         // Test next subset retrieval   
-        let next_subset = algoparam_get_next_set(as_voidptr(&tree), &mut basekey);
+        let next_subset = unsafe { algoparam_get_next_set(as_voidptr(&tree), &mut basekey) };
         assert_eq!(as_strref(next_subset.key), "subset2");
         let expected = (0x01u64 << 56) | 0x00ff_ffff_ffff_ffff;
         assert_eq!(basekey, expected);
         let subset2 = basekey;
-        let _ = algoparam_get_next_set(as_voidptr(&tree), &mut basekey);
+        let _ = unsafe { algoparam_get_next_set(as_voidptr(&tree), &mut basekey) };
         assert_eq!(basekey, KEY_NOT_FOUND);
 
         // Test first parameter retrieval
         basekey = KEY_NOT_FOUND;
-        let first_param = algoparam_get_first_param(as_voidptr(&tree), &mut basekey);
+        let first_param = unsafe { algoparam_get_first_param(as_voidptr(&tree), &mut basekey) };
         assert_eq!(as_strref(first_param.key), "param3");
         let expected = (0x02u64 << 56) | 0x00ff_ffff_ffff_ffff;
         assert_eq!(basekey, expected);
 
         // Test next parameter retrieval - this should fail
-        let _ = algoparam_get_next_param(as_voidptr(&tree), &mut basekey);
+        let _ = unsafe { algoparam_get_next_param(as_voidptr(&tree), &mut basekey) };
         assert_eq!(basekey, KEY_NOT_FOUND);
 
         // Test first parameter retrieval from subset1
         basekey = subset1;
-        let first_param_subset1 = algoparam_get_first_param(as_voidptr(&tree), &mut basekey);
+        let first_param_subset1 = unsafe { algoparam_get_first_param(as_voidptr(&tree), &mut basekey) };
         assert_eq!(as_strref(first_param_subset1.key), "param1_1");
         let expected = (0x0000 << 48) | 0x0000_ffff_ffff_ffff;
         assert_eq!(basekey, expected);
 
         // Test next parameter retrieval from subset1
-        let next_param_subset1 = algoparam_get_next_param(as_voidptr(&tree), &mut basekey);
+        let next_param_subset1 = unsafe { algoparam_get_next_param(as_voidptr(&tree), &mut basekey) };
         assert_eq!(as_strref(next_param_subset1.key), "param1_2");
         let expected = (0x0001u64 << 48) | 0x0000_ffff_ffff_ffff;
         assert_eq!(basekey, expected);
 
         // next retrieval should fail
-        let _ = algoparam_get_next_param(as_voidptr(&tree), &mut basekey);
+        let _ = unsafe { algoparam_get_next_param(as_voidptr(&tree), &mut basekey) };
         assert_eq!(basekey, KEY_NOT_FOUND);
 
         // Check that we can't find any sets
         basekey = subset1;
-        let _= algoparam_get_first_set(as_voidptr(&tree), &mut basekey);
+        let _= unsafe { algoparam_get_first_set(as_voidptr(&tree), &mut basekey) };
         assert_eq!(basekey, KEY_NOT_FOUND);
 
 
         // repeat for subset2
         basekey = subset2;
-        let first_param_subset2 = algoparam_get_first_param(as_voidptr(&tree), &mut basekey);
+        let first_param_subset2 = unsafe { algoparam_get_first_param(as_voidptr(&tree), &mut basekey) };
         assert_eq!(as_strref(first_param_subset2.key), "param2_1");
         let expected = (0x0100u64 << 48) | 0x0000_ffff_ffff_ffff;
         assert_eq!(basekey, expected);
 
         // Test next parameter retrieval from subset2
-        let next_param_subset2 = algoparam_get_next_param(as_voidptr(&tree), &mut basekey);
+        let next_param_subset2 = unsafe { algoparam_get_next_param(as_voidptr(&tree), &mut basekey) };
         assert_eq!(as_strref(next_param_subset2.key), "param2_2");
         let expected = (0x0101u64 << 48) | 0x0000_ffff_ffff_ffff;
 
         assert_eq!(basekey, expected);
 
         // next retrieval should fail
-        let _ = algoparam_get_next_param(as_voidptr(&tree), &mut basekey);
+        let _ = unsafe { algoparam_get_next_param(as_voidptr(&tree), &mut basekey) };
         assert_eq!(basekey, KEY_NOT_FOUND); 
 
         basekey = subset2;
-        let subsubset1= algoparam_get_first_set(as_voidptr(&tree), &mut basekey);
+        let subsubset1= unsafe { algoparam_get_first_set(as_voidptr(&tree), &mut basekey) };
         assert_eq!(as_strref(subsubset1.key),"mock");
 
-        let _= algoparam_get_next_set(as_voidptr(&tree), &mut basekey);
+        let _= unsafe { algoparam_get_next_set(as_voidptr(&tree), &mut basekey) };
         assert_eq!(basekey, KEY_NOT_FOUND);
 
 
     }
-        
 
-}
\ No newline at end of file
+    #[cfg(feature = "derive")]
+    mod derived {
+        use super::*;
+        use crate::Params;
+
+        #[derive(Params)]
+        struct Envelope {
+            #[param(name = "Attack", min = 0.001, max = 5.0, default = 0.01, unit = SECONDS)]
+            attack: AtomicF32,
+        }
+
+        #[derive(Params)]
+        struct Voice {
+            #[param(id = "cutoff", name = "Cutoff", min = 20.0, max = 20000.0, default = 1000.0, unit = HERTZ, taper = Skew { center: 1000.0 }, dependents = ["env.attack"])]
+            cutoff: AtomicF32,
+            #[params(id = "env", name = "Envelope")]
+            env: Arc<Envelope>,
+            #[param(name = "Mode", unit = INDEXED, value_strings = ["LP", "HP"])]
+            mode: AtomicF32,
+            #[param(name = "Level", min = -96.0, max = 0.0, unit = DECIBELS, flags = [READ_ONLY, METER])]
+            level: AtomicF32,
+            #[allow(dead_code)]
+            scratch: Vec<f32>,
+        }
+
+        #[test]
+        fn test_derive_params() {
+            let (tree, storage) = new_params::<Voice>("root", "Root");
+            assert_eq!(storage.cutoff.load(Ordering::Relaxed), 1000.0);
+            assert_eq!(storage.env.attack.load(Ordering::Relaxed), 0.01);
+
+            let cutoff = tree.find_param("cutoff").unwrap();
+            assert_eq!(cutoff.name.to_str().unwrap(), "Cutoff");
+            assert_eq!((cutoff.min, cutoff.max), (20.0, 20000.0));
+            assert_eq!(cutoff.dependents[0].to_str().unwrap(), "env.attack");
+            assert_eq!(cutoff.taper, crate::taper::Taper::Skew { center: 1000.0 });
+
+            let attack = tree.address_of("env.attack").unwrap();
+            assert_eq!(tree.find_param("env.attack").unwrap().name.to_str().unwrap(), "Attack");
+            tree.set(0.5, attack).unwrap();
+            assert_eq!(storage.env.attack.load(Ordering::Relaxed), 0.5);
+            assert_eq!(tree.get(attack).unwrap(), 0.5);
+
+            let mode = tree.find_param("mode").unwrap();
+            assert_eq!((mode.max, mode.value_strings.len()), (1.0, 2));
+
+            let level = tree.address_of("level").unwrap();
+            assert_eq!(tree.find_param("level").unwrap().flags, AlgoParamFlags::READ_ONLY | AlgoParamFlags::METER);
+            storage.level.store(-12.0, Ordering::Relaxed);
+            assert_eq!(tree.set(0.0, level), Err(SetParameterError::ReadOnly));
+            assert_eq!(tree.get(level).unwrap(), -12.0);
+            assert_eq!(tree.set(0.0, KEY_NOT_FOUND), Err(SetParameterError::UnknownAddress));
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        let storage = Arc::new(AtomicF32::new(0.0));
+        let param = AlgoParam::builder("cutoff").name("Cutoff").range(20.0, 20000.0).default(1000.0)
+            .unit(AlgoParamUnit::HERTZ).bind(&storage).dependents(&["q"]).build().unwrap();
+        assert_eq!(param.name.to_str().unwrap(), "Cutoff");
+        assert!(!param.dependents_as_raw().is_null());
+        (param.setter)(440.0);
+        assert_eq!((param.getter)(), 440.0);
+
+        assert_eq!(AlgoParam::builder("gain").bind(&storage).build().unwrap().name.to_str().unwrap(), "gain");
+        assert_eq!(AlgoParam::builder("x").range(1.0, 0.0).bind(&storage).build().err(), Some(AlgoParamError::InvalidRange { min: 1.0, max: 0.0 }));
+        assert_eq!(AlgoParam::builder("x").default(2.0).bind(&storage).build().err(), Some(AlgoParamError::DefaultOutOfRange { default: 2.0, min: 0.0, max: 1.0 }));
+        assert_eq!(AlgoParam::builder("x").build().err(), Some(AlgoParamError::Unbound));
+        assert_eq!(AlgoParam::builder("x\0").bind(&storage).build().err(), Some(AlgoParamError::NullByte("x\0".to_string())));
+    }
+
+    #[test]
+    fn test_bound_storage() {
+        let gain = Arc::new(Smooth::new_with_value(1.0));
+        let param = AlgoParam::bound_with("gain", "Gain", -60.0, 12.0, 0.0, AlgoParamUnit::DECIBELS, &gain, ValueTransform::DB_TO_GAIN);
+        (param.setter)(-20.0);
+        assert!((gain.get() - 0.1).abs() < 1e-6);
+        assert!(((param.getter)() + 20.0).abs() < 1e-4);
+
+        let mix = Arc::new(AtomicF32::new(0.0));
+        let param = AlgoParam::bound("mix", "Mix", 0.0, 1.0, 0.5, AlgoParamUnit::GENERIC, &mix);
+        (param.setter)(0.75);
+        assert_eq!(mix.load(Ordering::Relaxed), 0.75);
+    }
+
+    #[test]
+    fn test_value_strings() {
+        let storage = Arc::new(AtomicF32::new(0.0));
+        let param = AlgoParam::builder("mode").unit(AlgoParamUnit::INDEXED).value_strings(&["LP", "BP", "HP"]).bind(&storage).build().unwrap();
+        assert_eq!(param.max, 2.0);
+        let cparam = AlgoCParam::new(&param);
+        assert_eq!(as_strref(unsafe { *cparam.value_strings.add(2) }), "HP");
+        assert!(unsafe { *cparam.value_strings.add(3) }.is_null());
+
+        let err = AlgoParam::builder("mode").range(0.0, 3.0).value_strings(&["LP", "BP", "HP"]).bind(&storage).build().err();
+        assert_eq!(err, Some(AlgoParamError::ValueStringCount { expected: 4, found: 3 }));
+        let param = AlgoParam::bound("mode", "Mode", 0.0, 1.5, 0.0, AlgoParamUnit::INDEXED, &storage);
+        assert!(param.with_value_strings(&["a", "b"]).is_err());
+    }
+
+    #[test]
+    fn test_param_formatting() {
+        let storage = Arc::new(AtomicF32::new(0.0));
+        let mode = AlgoParam::builder("mode").unit(AlgoParamUnit::INDEXED).value_strings(&["LP", "HP"]).bind(&storage).build().unwrap();
+        assert_eq!(mode.format_value(1.0), "HP");
+        assert_eq!(mode.parse_value("lp"), Some(0.0));
+
+        let freq = AlgoParam::bound("freq", "Freq", 20.0, 20000.0, 1000.0, AlgoParamUnit::HERTZ, &storage);
+        assert_eq!(freq.format_value(1500.0), "1.5 kHz");
+        let freq = freq.with_formatter(|v| format!("{:.0}", v)).with_parser(|_| Some(1.0));
+        assert_eq!(freq.format_value(1500.0), "1500");
+        assert_eq!(freq.parse_value("anything"), Some(1.0));
+
+        let voices = AlgoParam::builder("voices").range(1.0, 16.0).custom_unit("voices").bind(&storage).build().unwrap();
+        assert_eq!(voices.format_value(8.0), "8.00 voices");
+        assert_eq!(voices.parse_value("4 voices"), Some(4.0));
+        assert_eq!(as_strref(AlgoCParam::new(&voices).unit_name), "voices");
+        assert!(AlgoCParam::new(&freq).unit_name.is_null());
+    }
+
+    #[test]
+    fn test_dependents_hook() {
+        // Switching the filter mode picks a matching resonance
+        let (mode, q) = (Arc::new(AtomicF32::new(0.0)), Arc::new(AtomicF32::new(0.7)));
+        let q_storage = q.clone();
+        let mut filter = AlgoParamSet::new("filter", "Filter");
+        filter.add(AlgoParamNode::Param(AlgoParam::builder("q").range(0.1, 10.0).default(0.7).bind(&q).build().unwrap())).unwrap();
+        filter.add(AlgoParamNode::Param(AlgoParam::builder("mode").unit(AlgoParamUnit::INDEXED).value_strings(&["LP", "BP"])
+            .dependents(&["q"]).update_dependents(move |mode| q_storage.store(if mode == 0.0 { 0.7 } else { 2.0 }, Ordering::Relaxed))
+            .bind(&mode).build().unwrap())).unwrap();
+        let mut tree = AlgoParamSet::new("root", "Root");
+        tree.add(AlgoParamNode::Param(AlgoParam::builder("gain").bind(&mode).build().unwrap())).unwrap();
+        tree.add(AlgoParamNode::ParamSet(filter)).unwrap();
+
+        let mut changed = Vec::new();
+        assert_eq!(tree.set_reporting(1.0, tree.address_of("filter.mode").unwrap(), &mut |a, v| changed.push((a, v))), Ok(1.0));
+        assert_eq!(changed, vec![(tree.address_of("filter.q").unwrap(), 2.0)]);
+
+        // No report when the dependent keeps its value
+        changed.clear();
+        tree.set_reporting(1.0, tree.address_of("filter.mode").unwrap(), &mut |a, v| changed.push((a, v))).unwrap();
+        assert!(changed.is_empty());
+        tree.set(0.0, tree.address_of("filter.mode").unwrap()).unwrap();
+        assert_eq!(q.load(Ordering::Relaxed), 0.7);
+    }
+
+    #[test]
+    fn test_reset_to_defaults() {
+        let (attack, release, level) = (Arc::new(AtomicF32::new(0.5)), Arc::new(AtomicF32::new(0.5)), Arc::new(AtomicF32::new(0.5)));
+        let mut env = AlgoParamSet::new("env", "Envelope");
+        env.add(AlgoParamNode::Param(AlgoParam::builder("attack").default(0.1).bind(&attack).build().unwrap())).unwrap();
+        env.add(AlgoParamNode::Param(AlgoParam::builder("release").default(0.2).bind(&release).build().unwrap())).unwrap();
+        let mut tree = AlgoParamSet::new("root", "Root");
+        tree.add(AlgoParamNode::ParamSet(env)).unwrap();
+        tree.add(AlgoParamNode::Param(AlgoParam::builder("level").meter(&Arc::new(Meter::default())).build().unwrap())).unwrap();
+        tree.add(AlgoParamNode::Param(AlgoParam::builder("out").default(1.0).bind(&level).build().unwrap())).unwrap();
+
+        assert_eq!(tree.reset_to_defaults_at(tree.address_of("env.release").unwrap()).unwrap(), 1);
+        assert_eq!((attack.load(Ordering::Relaxed), release.load(Ordering::Relaxed)), (0.5, 0.2));
+        assert_eq!(tree.reset_to_defaults_at(0x00ff_ffff_ffff_ffff).unwrap(), 2);
+        assert_eq!(attack.load(Ordering::Relaxed), 0.1);
+        assert_eq!(level.load(Ordering::Relaxed), 0.5);
+        // The meter is read-only
+        assert_eq!(tree.reset_to_defaults_at(KEY_NOT_FOUND).unwrap(), 3);
+        assert_eq!(level.load(Ordering::Relaxed), 1.0);
+        assert!(tree.reset_to_defaults_at(0x07ff_ffff_ffff_ffff).is_err());
+    }
+
+    #[test]
+    fn test_range_policy() {
+        let storage = Arc::new(AtomicF32::new(0.0));
+        let mode = AlgoParam::builder("mode").unit(AlgoParamUnit::INDEXED).value_strings(&["A", "B", "C"]).bind(&storage).build().unwrap();
+        let freq = AlgoParam::builder("freq").range(20.0, 20000.0).default(440.0).bind(&storage).build().unwrap();
+        let mut tree = AlgoParamSet::new("root", "Root");
+        tree.add(AlgoParamNode::Param(mode)).unwrap();
+        tree.add(AlgoParamNode::Param(freq)).unwrap();
+        let (mode, freq) = (tree.address_of("mode").unwrap(), tree.address_of("freq").unwrap());
+
+        assert_eq!(tree.set(1.6, mode), Ok(2.0));
+        assert_eq!(storage.load(Ordering::Relaxed), 2.0);
+        assert_eq!(tree.set(7.0, mode), Ok(2.0));
+        assert_eq!(tree.set(f32::NAN, freq), Ok(440.0));
+        assert_eq!(tree.set(f32::NEG_INFINITY, freq), Ok(20.0));
+
+        let tree = tree.with_range_policy(RangePolicy::Reject);
+        assert_eq!(tree.set(30000.0, freq), Err(SetParameterError::OutOfRange { value: 30000.0, min: 20.0, max: 20000.0 }));
+        assert_eq!(storage.load(Ordering::Relaxed), 20.0);
+        assert!(tree.set(f32::NAN, freq).is_err());
+        assert_eq!(tree.set(0.4, mode), Ok(0.0));
+
+        // Inverted and NaN ranges are refused by the builder. The plain constructors take them, set doesn't panic.
+        assert_eq!(AlgoParam::builder("inverted").range(1.0, 0.0).bind(&storage).build().err(), Some(AlgoParamError::InvalidRange { min: 1.0, max: 0.0 }));
+        let inverted = AlgoParam::bound("inverted", "Inverted", 1.0, 0.0, 0.0, AlgoParamUnit::GENERIC, &storage);
+        assert_eq!(inverted.effective_value(0.5, RangePolicy::Clamp), Ok(0.0));
+        let undefined = AlgoParam::bound("undefined", "Undefined", f32::NAN, 1.0, 0.0, AlgoParamUnit::GENERIC, &storage);
+        assert_eq!(undefined.effective_value(2.0, RangePolicy::Clamp), Ok(1.0));
+    }
+
+    #[test]
+    fn test_meter_param() {
+        use crate::util::MeterMode;
+        let meter = Arc::new(Meter::new(MeterMode::Peak).with_hold(0.1).with_decay(60.0));
+        let mut tree = AlgoParamSet::new("root", "Root");
+        tree.add(AlgoParamNode::Param(AlgoParam::builder("level").meter(&meter).build().unwrap())).unwrap();
+        let level = tree.address_of("level").unwrap();
+        assert!(tree.get_param(level).unwrap().flags.contains(AlgoParamFlags::READ_ONLY | AlgoParamFlags::METER));
+
+        meter.process(&[0.25, -0.5, 0.1], 48000.0);
+        let mut values = [0.0; 2];
+        assert_eq!(tree.get_many(&[level, KEY_NOT_FOUND], &mut values), 1);
+        assert!((values[0] - -6.0206).abs() < 1e-3);
+        assert_eq!(tree.set(0.0, level), Err(SetParameterError::ReadOnly));
+
+        // Held for 0.1s, then falls 60 dB/s
+        meter.update(0.0, 0.1);
+        assert_eq!(meter.level(), 0.5);
+        meter.update(0.0, 0.5);
+        assert!((tree.get(level).unwrap() - -36.0206).abs() < 1e-3);
+
+        // Levels outside the range are reported at its ends
+        meter.update(0.0, 10.0);
+        assert_eq!(tree.get(level).unwrap(), -96.0);
+        meter.process(&[4.0], 48000.0);
+        assert_eq!(tree.get(level).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_param_taper() {
+        let storage = Arc::new(AtomicF32::new(0.0));
+        let cutoff = AlgoParam::builder("cutoff").range(20.0, 20000.0).taper(Taper::Logarithmic).bind(&storage).build().unwrap();
+        assert!((cutoff.from_normalized(0.5) - 632.456).abs() < 0.01);
+        assert_eq!(cutoff.to_normalized(20000.0), 1.0);
+        assert_eq!(AlgoCParam::new(&cutoff).taper, crate::taper::TaperKind::LOGARITHMIC as i32);
+
+        let err = AlgoParam::builder("gain").range(-60.0, 0.0).taper(Taper::Logarithmic).bind(&storage).build().err();
+        assert_eq!(err, Some(AlgoParamError::InvalidTaper(Taper::Logarithmic)));
+    }
+}
@@ -0,0 +1,174 @@
+use std::{ffi::{c_char, CString}, ops::BitOr, ptr::null};
+
+use crate::fault::FfiDefault;
+
+// Plugin identity of an algorithm. Host wrappers (AU, VST3, CLAP ...) generate their manifests from this.
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlgoCategory {
+    EFFECT,
+    INSTRUMENT,
+    MIDIEFFECT,
+    ANALYZER,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AlgoVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl AlgoVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> AlgoVersion {
+        AlgoVersion { major, minor, patch }
+    }
+}
+
+impl std::fmt::Display for AlgoVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlgoIdKind {
+    NONE,
+    FOURCC,
+    UUID,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlgoUniqueId {
+    None,
+    FourCC([u8; 4]),
+    Uuid([u8; 16]),
+}
+
+impl AlgoUniqueId {
+    pub const fn fourcc(code: &[u8; 4]) -> AlgoUniqueId {
+        AlgoUniqueId::FourCC(*code)
+    }
+
+    fn kind(&self) -> AlgoIdKind {
+        match self {
+            AlgoUniqueId::None => AlgoIdKind::NONE,
+            AlgoUniqueId::FourCC(_) => AlgoIdKind::FOURCC,
+            AlgoUniqueId::Uuid(_) => AlgoIdKind::UUID,
+        }
+    }
+
+    // Four-char codes occupy the first four bytes, the rest is zero
+    fn as_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        match self {
+            AlgoUniqueId::None => {},
+            AlgoUniqueId::FourCC(code) => bytes[..4].copy_from_slice(code),
+            AlgoUniqueId::Uuid(uuid) => bytes = *uuid,
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AlgoFeatures(pub u32);
+
+impl AlgoFeatures {
+    pub const NONE: AlgoFeatures = AlgoFeatures(0);
+    pub const AUDIO_INPUT: AlgoFeatures = AlgoFeatures(1 << 0);
+    pub const AUDIO_OUTPUT: AlgoFeatures = AlgoFeatures(1 << 1);
+    pub const MIDI_INPUT: AlgoFeatures = AlgoFeatures(1 << 2);
+    pub const MIDI_OUTPUT: AlgoFeatures = AlgoFeatures(1 << 3);
+    pub const SIDECHAIN: AlgoFeatures = AlgoFeatures(1 << 4);
+
+    pub fn contains(&self, other: AlgoFeatures) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for AlgoFeatures {
+    type Output = AlgoFeatures;
+
+    fn bitor(self, rhs: AlgoFeatures) -> AlgoFeatures {
+        AlgoFeatures(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AlgoDescriptor {
+    pub name: CString,
+    pub vendor: CString,
+    pub version: AlgoVersion,
+    pub category: AlgoCategory,
+    pub unique_id: AlgoUniqueId,
+    pub features: AlgoFeatures,
+}
+
+impl AlgoDescriptor {
+    pub fn new(name: &str, vendor: &str, version: AlgoVersion, category: AlgoCategory,
+                unique_id: AlgoUniqueId, features: AlgoFeatures) -> AlgoDescriptor {
+        let _name = CString::new(name).expect("null byte in algorithm name");
+        let _vendor = CString::new(vendor).expect("null byte in vendor name");
+        AlgoDescriptor { name: _name, vendor: _vendor, version, category, unique_id, features }
+    }
+}
+
+impl Default for AlgoDescriptor {
+    // Used for algorithms that don't describe themselves
+    fn default() -> Self {
+        AlgoDescriptor::new("Unnamed", "Unknown", AlgoVersion::new(0, 0, 0), AlgoCategory::EFFECT,
+            AlgoUniqueId::None, AlgoFeatures::AUDIO_INPUT | AlgoFeatures::AUDIO_OUTPUT)
+    }
+}
+
+// C interface. The strings are owned by the descriptor and stay valid as long as the module (or for
+// soundmodule_describe_algorithm, the process) lives.
+
+#[repr(C)]
+pub struct AlgoCDescriptor {
+    pub name: *const c_char,
+    pub vendor: *const c_char,
+    pub version_major: u32,
+    pub version_minor: u32,
+    pub version_patch: u32,
+    pub category: i32,
+    pub id_kind: i32,
+    pub id: [u8; 16],
+    pub features: u32,
+}
+
+impl AlgoCDescriptor {
+    pub fn null() -> AlgoCDescriptor {
+        AlgoCDescriptor {
+            name: null(),
+            vendor: null(),
+            version_major: 0,
+            version_minor: 0,
+            version_patch: 0,
+            category: 0,
+            id_kind: AlgoIdKind::NONE as i32,
+            id: [0; 16],
+            features: 0,
+        }
+    }
+
+    pub fn new(from: &AlgoDescriptor) -> AlgoCDescriptor {
+        AlgoCDescriptor {
+            name: from.name.as_ptr(),
+            vendor: from.vendor.as_ptr(),
+            version_major: from.version.major,
+            version_minor: from.version.minor,
+            version_patch: from.version.patch,
+            category: from.category as i32,
+            id_kind: from.unique_id.kind() as i32,
+            id: from.unique_id.as_bytes(),
+            features: from.features.0,
+        }
+    }
+}
+
+impl FfiDefault for AlgoCDescriptor {
+    fn ffi_default() -> Self { AlgoCDescriptor::null() }
+}
@@ -0,0 +1,222 @@
+use std::{mem, sync::Arc};
+
+use crate::{algoparam::AlgoParamSet, util::BoundedQueue};
+
+// Sample accurate parameter changes. Control threads push ParamEvents into a queue, the render side
+// collects them at the start of each block and splits the block at the event times, so the setter for
+// an event runs right before the first sample it applies to. Ramps are rendered as steps of RAMP_STEP samples.
+// Scheduled setters therefore run on the audio thread.
+//
+// Batches are sets of parameter values that the render side applies together at the start of a block,
+// so process never sees half of a preset. Applied batches go back to the control side through a second
+// queue, where they are freed or reused, so the audio thread doesn't deallocate.
+//
+// Addresses are resolved against the parameter tree of a generation (tree::ParamTree). Events and batch
+// values carry that generation and are dropped if the tree has changed by the time they would be applied,
+// as their address may name a different parameter by then. Hosts reschedule after ParamChange::TreeChanged.
+
+// Events that can wait in the queue and in the render side's pending list
+pub const EVENT_CAPACITY: usize = 1024;
+// Ramps that can run at the same time. Further ramps jump to their target.
+pub const MAX_RAMPS: usize = 64;
+// Samples between ramp updates
+pub const RAMP_STEP: usize = 32;
+// Committed batches that can wait for the render side
+pub const BATCH_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParamEvent {
+    pub address: u64,
+    pub value: f32,
+    // In samples rendered since the module was created. Times before the next block apply at its start.
+    pub sample_time: u64,
+    // Samples to move from the current value to value. 0 jumps.
+    pub ramp: u32,
+}
+
+// An event with the tree generation its address was resolved against
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueuedEvent {
+    pub event: ParamEvent,
+    pub generation: u64,
+}
+
+pub type EventQueue = BoundedQueue<QueuedEvent>;
+
+pub fn new_event_queue() -> Arc<EventQueue> {
+    Arc::new(BoundedQueue::new(EVENT_CAPACITY))
+}
+
+#[derive(Debug, Default)]
+pub struct ParamBatch {
+    // Address, value and the tree generation the address was resolved against
+    values: Vec<(u64, f32, u64)>,
+}
+
+impl ParamBatch {
+    // Later values for the same address win. See ControlHandle::stage_parameter.
+    pub(crate) fn set(&mut self, address: u64, value: f32, generation: u64) {
+        self.values.push((address, value, generation));
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+}
+
+pub type BatchQueue = BoundedQueue<ParamBatch>;
+
+// Committed batches to the render side and applied ones back
+pub struct BatchChannel {
+    commits: BatchQueue,
+    returns: BatchQueue,
+}
+
+impl BatchChannel {
+    pub fn new() -> Arc<BatchChannel> {
+        // begin and commit take all applied batches back first, so at most the batches waiting at that point
+        // plus the one committed then can come back before the next take: returns can't overflow
+        Arc::new(BatchChannel { commits: BoundedQueue::new(BATCH_CAPACITY), returns: BoundedQueue::new(2 * BATCH_CAPACITY) })
+    }
+
+    // An empty batch, reusing the storage of an applied one if there is any. Frees the other applied batches.
+    pub fn begin(&self) -> ParamBatch {
+        let mut batch = self.returns.pop().unwrap_or_default();
+        while self.returns.pop().is_some() {}
+        batch.clear();
+        batch
+    }
+
+    // Hands the batch back if too many batches are waiting
+    pub fn commit(&self, batch: ParamBatch) -> Result<(), ParamBatch> {
+        while self.returns.pop().is_some() {}
+        self.commits.push(batch)
+    }
+
+    // Render side: applies all committed batches to params, the tree of the given generation, passing
+    // changed dependents to report (see AlgoParamSet::set_reporting). Values staged against another
+    // generation are dropped. Returns the number of batches applied.
+    pub fn apply(&self, params: &AlgoParamSet, generation: u64, report: &mut dyn FnMut(u64, f32)) -> usize {
+        let mut applied = 0;
+        while let Some(batch) = self.commits.pop() {
+            for (address, value, _) in batch.values.iter().filter(|v| v.2 == generation) {
+                let _ = params.set_reporting(*value, *address, report);
+            }
+            // Can't fail (see new). Leaked rather than freed on the audio thread if it ever did.
+            if let Err(batch) = self.returns.push(batch) {
+                mem::forget(batch);
+            }
+            applied += 1;
+        }
+        applied
+    }
+}
+
+struct Ramp {
+    address: u64,
+    from: f32,
+    to: f32,
+    elapsed: u32,
+    duration: u32,
+    generation: u64,
+}
+
+// Render side of the event queue. Holds no locks and doesn't allocate after creation.
+pub struct EventScheduler {
+    queue: Arc<EventQueue>,
+    pending: Vec<QueuedEvent>,   // sorted by sample_time
+    ramps: Vec<Ramp>,
+    sample_time: u64,
+}
+
+impl EventScheduler {
+    pub fn new(queue: Arc<EventQueue>) -> EventScheduler {
+        EventScheduler {
+            queue,
+            pending: Vec::with_capacity(EVENT_CAPACITY),
+            ramps: Vec::with_capacity(MAX_RAMPS),
+            sample_time: 0,
+        }
+    }
+
+    // Start of the next sub-block
+    pub fn sample_time(&self) -> u64 {
+        self.sample_time
+    }
+
+    // Nothing pending or ramping, the block can be rendered in one go
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty() && self.ramps.is_empty()
+    }
+
+    // Moves queued events to the pending list. Events stay queued while the list is full. Events and ramps
+    // resolved against another tree generation than the current one are dropped.
+    pub fn collect(&mut self, generation: u64) {
+        self.pending.retain(|queued| queued.generation == generation);
+        self.ramps.retain(|ramp| ramp.generation == generation);
+        while self.pending.len() < self.pending.capacity() {
+            let Some(queued) = self.queue.pop() else { break };
+            if queued.generation != generation {
+                continue;
+            }
+            // Stable, so events for the same time keep their order
+            let idx = self.pending.partition_point(|e| e.event.sample_time <= queued.event.sample_time);
+            self.pending.insert(idx, queued);
+        }
+    }
+
+    // Applies the events due at the current sample time and updates the ramps, passing changed dependents
+    // to report. Returns the length of the sub-block to render next, at most remaining. If split is false
+    // the rest of the block is rendered in one go, so the events due anywhere in it are applied now.
+    pub fn advance(&mut self, params: &AlgoParamSet, remaining: usize, split: bool, report: &mut dyn FnMut(u64, f32)) -> usize {
+        let now = self.sample_time;
+        let horizon = if split { now } else { now + remaining.saturating_sub(1) as u64 };
+        let due = self.pending.partition_point(|e| e.event.sample_time <= horizon);
+        for QueuedEvent { event, generation } in self.pending.drain(..due) {
+            self.ramps.retain(|ramp| ramp.address != event.address);
+            if event.ramp == 0 || self.ramps.len() == MAX_RAMPS {
+                let _ = params.set_reporting(event.value, event.address, report);
+            } else if let Ok(from) = params.get(event.address) {
+                self.ramps.push(Ramp { address: event.address, from, to: event.value, elapsed: 0, duration: event.ramp, generation });
+            }
+        }
+
+        let mut len = remaining;
+        self.ramps.retain(|ramp| {
+            if ramp.elapsed >= ramp.duration {
+                let _ = params.set_reporting(ramp.to, ramp.address, report);
+                false
+            } else {
+                let position = ramp.elapsed as f32 / ramp.duration as f32;
+                let _ = params.set_reporting(ramp.from + (ramp.to - ramp.from) * position, ramp.address, report);
+                len = len.min(RAMP_STEP).min((ramp.duration - ramp.elapsed) as usize);
+                true
+            }
+        });
+        if let Some(next) = self.pending.first() {
+            len = len.min((next.event.sample_time - now) as usize);
+        }
+        if !split {
+            len = remaining;
+        }
+
+        for ramp in self.ramps.iter_mut() {
+            ramp.elapsed = ramp.elapsed.saturating_add(len as u32);
+        }
+        self.sample_time += len as u64;
+        len
+    }
+
+    // Advances time without events, for blocks rendered in one go
+    pub fn skip(&mut self, len: usize) {
+        self.sample_time += len as u64;
+    }
+}
@@ -0,0 +1,118 @@
+use std::{any::Any, ffi::{c_char, c_void, CString}, panic::{self, AssertUnwindSafe}, ptr::{null, null_mut}, sync::Mutex};
+
+use crate::util::copy_to_c_buffer;
+
+// Panic containment for the C boundary. Unwinding out of an extern "C" function is undefined behaviour,
+// so every exported function runs its body through ffi_guard. A caught panic is recorded as the last error
+// and the function returns the neutral value given by FfiDefault.
+
+static LAST_ERROR: Mutex<Option<CString>> = Mutex::new(None);
+
+/// Value returned from an exported function when its body panicked
+pub trait FfiDefault {
+    fn ffi_default() -> Self;
+}
+
+impl FfiDefault for () {
+    fn ffi_default() -> Self {}
+}
+
+impl FfiDefault for bool {
+    fn ffi_default() -> Self { false }
+}
+
+impl FfiDefault for f32 {
+    fn ffi_default() -> Self { 0.0 }
+}
+
+impl FfiDefault for usize {
+    fn ffi_default() -> Self { 0 }
+}
+
+impl FfiDefault for u64 {
+    fn ffi_default() -> Self { 0 }
+}
+
+impl FfiDefault for *const c_void {
+    fn ffi_default() -> Self { null() }
+}
+
+impl FfiDefault for *mut c_void {
+    fn ffi_default() -> Self { null_mut() }
+}
+
+impl FfiDefault for *const *const c_char {
+    fn ffi_default() -> Self { null() }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.as_str()
+    } else {
+        "unknown panic payload"
+    }
+}
+
+pub fn set_last_error(msg: &str) {
+    // Interior null bytes would truncate the message on the C side anyway
+    let msg = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+    *LAST_ERROR.lock().unwrap_or_else(|e| e.into_inner()) = Some(msg);
+}
+
+/// Runs f, catching any panic. Returns None and records the last error if f panicked.
+pub fn catch<R>(context: &str, f: impl FnOnce() -> R) -> Option<R> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(v) => Some(v),
+        Err(payload) => {
+            set_last_error(&format!("panic in {}: {}", context, panic_message(payload.as_ref())));
+            None
+        }
+    }
+}
+
+/// Used by the reexport macros to wrap every exported function
+pub fn ffi_guard<R: FfiDefault>(context: &str, f: impl FnOnce() -> R) -> R {
+    catch(context, f).unwrap_or_else(R::ffi_default)
+}
+
+// API functions without name mangling
+
+/// Copies the last error message (null terminated, possibly truncated) into buf and returns the full
+/// length of the message excluding the terminator. Returns 0 if no error has been recorded.
+///
+/// # Safety
+/// buf must be NULL or point to len writable bytes.
+pub unsafe fn soundmodule_last_error(buf: *mut c_char, len: usize) -> usize {
+    let guard = LAST_ERROR.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(msg) = guard.as_ref() else {
+        return 0;
+    };
+    unsafe { copy_to_c_buffer(msg.as_bytes(), buf, len) }
+}
+
+pub fn soundmodule_clear_error() {
+    *LAST_ERROR.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_panic_is_recorded() {
+        let v: f32 = ffi_guard("test_fn", || panic!("boom"));
+        assert_eq!(v, 0.0);
+
+        let mut buf = [0 as c_char; 8];
+        let len = unsafe { soundmodule_last_error(buf.as_mut_ptr(), buf.len()) };
+        assert_eq!(len, "panic in test_fn: boom".len());
+        // Truncated, but still null terminated
+        let s = unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) };
+        assert_eq!(s.to_str().unwrap(), "panic i");
+
+        soundmodule_clear_error();
+        assert_eq!(unsafe { soundmodule_last_error(null_mut(), 0) }, 0);
+    }
+}
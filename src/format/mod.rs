@@ -0,0 +1,228 @@
+use crate::algoparam::AlgoParamUnit;
+
+// Display strings for parameter values and parsing of user input, per AlgoParamUnit.
+// Parsing accepts what format produces, plain numbers and a few common alternatives ("1k", "50%", "1/8T").
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+// Beat lengths are in quarter notes. Plain, dotted and triplet variants of 1/1 .. 1/64.
+const NOTE_DIVISIONS: [u32; 7] = [1, 2, 4, 8, 16, 32, 64];
+
+impl AlgoParamUnit {
+    pub fn format(&self, value: f32) -> String {
+        if value.is_nan() {
+            return "NaN".to_string();
+        }
+        match self {
+            AlgoParamUnit::INDEXED | AlgoParamUnit::MIDICONTROLLER | AlgoParamUnit::MIDI2CONTROLLER => format!("{}", value.round() as i64),
+            AlgoParamUnit::BOOLEAN => if value >= 0.5 { "On".to_string() } else { "Off".to_string() },
+            AlgoParamUnit::PERCENT => format!("{:.1} %", value),
+            AlgoParamUnit::SECONDS => format_seconds(value),
+            AlgoParamUnit::MILLISECONDS => format_seconds(value / 1000.0),
+            AlgoParamUnit::SAMPLES => format!("{} smp", value.round() as i64),
+            AlgoParamUnit::PHASE => format!("{:.2} rad", value),
+            AlgoParamUnit::RATE => format!("{:.2}x", value),
+            AlgoParamUnit::HERTZ => {
+                if value.abs() >= 1000.0 {
+                    format!("{:.1} kHz", value / 1000.0)
+                } else {
+                    format!("{:.1} Hz", value)
+                }
+            },
+            AlgoParamUnit::CENTS | AlgoParamUnit::ABSOLUTECENTS => format!("{:.0} ct", value),
+            AlgoParamUnit::SEMITONES => format!("{:.1} st", value),
+            AlgoParamUnit::MIDINOTENUMBER => format_note(value),
+            AlgoParamUnit::DECIBELS => {
+                if value == f32::NEG_INFINITY {
+                    "-inf dB".to_string()
+                } else {
+                    format!("{:.1} dB", value)
+                }
+            },
+            AlgoParamUnit::LINEARGAIN => format!("{:.3}", value),
+            AlgoParamUnit::DEGREES => format!("{:.1}°", value),
+            AlgoParamUnit::PAN => format_pan(value),
+            AlgoParamUnit::METERS => format!("{:.2} m", value),
+            AlgoParamUnit::OCTAVES => format!("{:.2} oct", value),
+            AlgoParamUnit::BPM => format!("{:.1} BPM", value),
+            AlgoParamUnit::BEATS => format_beats(value),
+            AlgoParamUnit::RATIO => format!("{:.1}:1", value),
+            AlgoParamUnit::GENERIC | AlgoParamUnit::EQUALPOWERCROSSFADE | AlgoParamUnit::MIXERFADERCURVE1
+                | AlgoParamUnit::CUSTOMUNIT => format!("{:.2}", value),
+        }
+    }
+
+    pub fn parse(&self, text: &str) -> Option<f32> {
+        let text = text.trim();
+        match self {
+            AlgoParamUnit::BOOLEAN => match text.to_ascii_lowercase().as_str() {
+                "on" | "true" | "yes" => Some(1.0),
+                "off" | "false" | "no" => Some(0.0),
+                _ => parse_number(text, &[]),
+            },
+            AlgoParamUnit::PERCENT => parse_number(text, &[("%", 1.0)]),
+            AlgoParamUnit::SECONDS => parse_number(text, &[("ms", 0.001), ("s", 1.0)]),
+            AlgoParamUnit::MILLISECONDS => parse_number(text, &[("ms", 1.0), ("s", 1000.0)]),
+            AlgoParamUnit::SAMPLES => parse_number(text, &[("smp", 1.0), ("samples", 1.0)]),
+            AlgoParamUnit::PHASE => parse_number(text, &[("rad", 1.0)]),
+            AlgoParamUnit::RATE => parse_number(text, &[("x", 1.0)]),
+            AlgoParamUnit::HERTZ => parse_number(text, &[("khz", 1000.0), ("hz", 1.0), ("k", 1000.0)]),
+            AlgoParamUnit::CENTS | AlgoParamUnit::ABSOLUTECENTS => parse_number(text, &[("cents", 1.0), ("ct", 1.0)]),
+            AlgoParamUnit::SEMITONES => parse_number(text, &[("st", 1.0)]),
+            AlgoParamUnit::MIDINOTENUMBER => parse_note(text).or_else(|| parse_number(text, &[])),
+            AlgoParamUnit::DECIBELS => {
+                if text.to_ascii_lowercase().starts_with("-inf") {
+                    Some(f32::NEG_INFINITY)
+                } else {
+                    parse_number(text, &[("db", 1.0)])
+                }
+            },
+            AlgoParamUnit::LINEARGAIN => parse_number(text, &[("x", 1.0)]),
+            AlgoParamUnit::DEGREES => parse_number(text, &[("°", 1.0), ("deg", 1.0)]),
+            AlgoParamUnit::PAN => parse_pan(text),
+            AlgoParamUnit::METERS => parse_number(text, &[("km", 1000.0), ("cm", 0.01), ("m", 1.0)]),
+            AlgoParamUnit::OCTAVES => parse_number(text, &[("oct", 1.0)]),
+            AlgoParamUnit::BPM => parse_number(text, &[("bpm", 1.0)]),
+            AlgoParamUnit::BEATS => parse_beats(text).or_else(|| parse_number(text, &[("beats", 1.0)])),
+            AlgoParamUnit::RATIO => {
+                let ratio = text.strip_suffix(":1").unwrap_or(text);
+                parse_number(ratio, &[])
+            },
+            _ => parse_number(text, &[]),
+        }
+    }
+}
+
+fn format_seconds(seconds: f32) -> String {
+    if seconds.abs() < 1.0 {
+        format!("{:.1} ms", seconds * 1000.0)
+    } else {
+        format!("{:.2} s", seconds)
+    }
+}
+
+// MIDI note 60 is C4
+fn format_note(value: f32) -> String {
+    let note = value.round() as i32;
+    format!("{}{}", NOTE_NAMES[note.rem_euclid(12) as usize], note.div_euclid(12) - 1)
+}
+
+fn parse_note(text: &str) -> Option<f32> {
+    let upper = text.to_ascii_uppercase();
+    let letter = upper.chars().next()?;
+    let base = [0, 2, 4, 5, 7, 9, 11]["CDEFGAB".find(letter)?];
+    let rest = &upper[1..];
+    let (accidental, octave) = if let Some(r) = rest.strip_prefix('#') {
+        (1, r)
+    } else if let Some(r) = rest.strip_prefix('B').filter(|r| !r.is_empty()) {
+        (-1, r)
+    } else {
+        (0, rest)
+    };
+    let octave: i32 = octave.parse().ok()?;
+    Some(((octave + 1) * 12 + base + accidental) as f32)
+}
+
+// Pan values run from -1 (left) to 1 (right) and are shown in percent
+fn format_pan(value: f32) -> String {
+    let percent = (value * 100.0).round() as i32;
+    match percent {
+        0 => "C".to_string(),
+        p if p < 0 => format!("L{}", -p),
+        p => format!("R{}", p),
+    }
+}
+
+fn parse_pan(text: &str) -> Option<f32> {
+    let upper = text.to_ascii_uppercase();
+    if upper == "C" {
+        Some(0.0)
+    } else if let Some(l) = upper.strip_prefix('L') {
+        Some(-l.trim().parse::<f32>().ok()? / 100.0)
+    } else if let Some(r) = upper.strip_prefix('R') {
+        Some(r.trim().parse::<f32>().ok()? / 100.0)
+    } else {
+        parse_number(text, &[])
+    }
+}
+
+fn format_beats(beats: f32) -> String {
+    for d in NOTE_DIVISIONS {
+        let plain = 4.0 / d as f32;
+        if (beats - plain).abs() < 1e-4 {
+            return format!("1/{}", d);
+        }
+        if (beats - plain * 1.5).abs() < 1e-4 {
+            return format!("1/{} dotted", d);
+        }
+        if (beats - plain * 2.0 / 3.0).abs() < 1e-4 {
+            return format!("1/{} triplet", d);
+        }
+    }
+    format!("{:.2} beats", beats)
+}
+
+fn parse_beats(text: &str) -> Option<f32> {
+    let lower = text.to_ascii_lowercase();
+    let (fraction, modifier) = if let Some(f) = lower.strip_suffix("dotted").or_else(|| lower.strip_suffix('.')).or_else(|| lower.strip_suffix('d')) {
+        (f.trim(), 1.5)
+    } else if let Some(f) = lower.strip_suffix("triplet").or_else(|| lower.strip_suffix('t')) {
+        (f.trim(), 2.0 / 3.0)
+    } else {
+        (lower.as_str(), 1.0)
+    };
+    let (num, den) = fraction.split_once('/')?;
+    let num: f32 = num.trim().parse().ok()?;
+    let den: f32 = den.trim().parse().ok()?;
+    if den == 0.0 {
+        return None;
+    }
+    Some(4.0 * num / den * modifier)
+}
+
+// Parses a number followed by an optional (case insensitive) suffix, scaling by the suffix multiplier.
+// Longer suffixes must come first in the list.
+fn parse_number(text: &str, suffixes: &[(&str, f32)]) -> Option<f32> {
+    let lower = text.trim().to_ascii_lowercase();
+    for (suffix, multiplier) in suffixes {
+        if let Some(number) = lower.strip_suffix(suffix) && let Ok(v) = number.trim().parse::<f32>() {
+            return Some(v * multiplier);
+        }
+    }
+    lower.parse::<f32>().ok().filter(|v| !v.is_nan())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_and_parse() {
+        let cases = [
+            (AlgoParamUnit::HERTZ, 1200.0, "1.2 kHz"),
+            (AlgoParamUnit::HERTZ, 440.0, "440.0 Hz"),
+            (AlgoParamUnit::DECIBELS, -6.0, "-6.0 dB"),
+            (AlgoParamUnit::BEATS, 0.75, "1/8 dotted"),
+            (AlgoParamUnit::BEATS, 1.0 / 3.0, "1/8 triplet"),
+            (AlgoParamUnit::BEATS, 4.0, "1/1"),
+            (AlgoParamUnit::MIDINOTENUMBER, 61.0, "C#4"),
+            (AlgoParamUnit::MIDINOTENUMBER, 0.0, "C-1"),
+            (AlgoParamUnit::PAN, -0.3, "L30"),
+            (AlgoParamUnit::PAN, 0.0, "C"),
+            (AlgoParamUnit::SECONDS, 0.25, "250.0 ms"),
+            (AlgoParamUnit::BOOLEAN, 1.0, "On"),
+            (AlgoParamUnit::RATIO, 4.0, "4.0:1"),
+        ];
+        for (unit, value, text) in cases {
+            assert_eq!(unit.format(value), text);
+            let parsed = unit.parse(text).unwrap();
+            assert!((parsed - value).abs() < 1e-3, "{:?} {} parsed as {}", unit, text, parsed);
+        }
+
+        assert_eq!(AlgoParamUnit::HERTZ.parse("2k"), Some(2000.0));
+        assert_eq!(AlgoParamUnit::MIDINOTENUMBER.parse("Bb3"), Some(58.0));
+        assert_eq!(AlgoParamUnit::BEATS.parse("1/16T"), Some(1.0 / 6.0));
+        assert_eq!(AlgoParamUnit::MILLISECONDS.parse("1.5 s"), Some(1500.0));
+        assert_eq!(AlgoParamUnit::GENERIC.parse("abc"), None);
+    }
+}
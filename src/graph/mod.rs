@@ -0,0 +1,430 @@
+use std::{fmt, sync::{atomic::Ordering, Arc, Mutex}};
+
+use crate::{algoparam::{AlgoParam, AlgoParamNode, AlgoParamSet, AlgoParamUnit, ValueTransform}, notify::{ChangeChannel, ParamNotifier},
+            sub_block, util::AtomicF32, Algorithm, BoundAlgorithm, DynAlgorithm, MAX_SPLIT_CHANNELS};
+
+// An algorithm made of other algorithms, e.g. EQ -> compressor -> saturator. Every node of the graph is a
+// set in the graph's parameter set, named by its identifier:
+//
+//   Algorithm    the algorithm's own set
+//   Serial       the sets of its nodes, each node processing the output of the one before
+//   Parallel     the sets of its nodes, which all process the input, and a "<node>_level" gain for each.
+//                The output is the sum of the nodes' outputs times their levels.
+//   WetDry       the set of its node and "mix", the percentage of the node's output blended with the input
+//
+// The root node's set is the graph's set itself, so Graph::new(GraphNode::serial("chain", "Chain", vec![eq, comp]))
+// has the parameters "eq.gain", "comp.ratio" and so on.
+//
+// Graphs render blocks in parts of GRAPH_CHUNK samples, using buffers allocated in init. Up to
+// MAX_SPLIT_CHANNELS channels are processed, further output channels are silent.
+
+pub const GRAPH_CHUNK: usize = 256;
+
+// A parameter set holds up to 254 children: a serial node's nodes, or a parallel node's nodes with their levels
+const MAX_SERIAL_NODES: usize = 254;
+const MAX_PARALLEL_NODES: usize = 127;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GraphError {
+    TooManyNodes { identifier: String, count: usize, max: usize },
+    // Identifiers and names become C strings
+    NullByte(String),
+    // Two children of the node's set would share an identifier, including the "<node>_level" and "mix"
+    // parameters the graph adds, so one of them couldn't be found by keypath
+    DuplicateIdentifier { parent: String, identifier: String },
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::TooManyNodes { identifier, count, max } => write!(f, "Node '{}' has {} nodes, at most {} are allowed", identifier, count, max),
+            GraphError::NullByte(s) => write!(f, "'{}' contains a null byte", s.escape_debug()),
+            GraphError::DuplicateIdentifier { parent, identifier } => write!(f, "Node '{}' has more than one '{}'", parent, identifier),
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+pub enum GraphNode {
+    Algorithm { identifier: String, name: String, algo: Box<dyn DynAlgorithm> },
+    Serial { identifier: String, name: String, nodes: Vec<GraphNode> },
+    Parallel { identifier: String, name: String, nodes: Vec<GraphNode> },
+    WetDry { identifier: String, name: String, node: Box<GraphNode> },
+}
+
+impl GraphNode {
+    pub fn algorithm(identifier: &str, name: &str, algo: impl Algorithm + 'static) -> GraphNode {
+        GraphNode::dyn_algorithm(identifier, name, Box::new(algo))
+    }
+
+    // For algorithms from the registry (registry::create_algorithm)
+    pub fn dyn_algorithm(identifier: &str, name: &str, algo: Box<dyn DynAlgorithm>) -> GraphNode {
+        GraphNode::Algorithm { identifier: identifier.to_string(), name: name.to_string(), algo }
+    }
+
+    pub fn serial(identifier: &str, name: &str, nodes: Vec<GraphNode>) -> GraphNode {
+        GraphNode::Serial { identifier: identifier.to_string(), name: name.to_string(), nodes }
+    }
+
+    pub fn parallel(identifier: &str, name: &str, nodes: Vec<GraphNode>) -> GraphNode {
+        GraphNode::Parallel { identifier: identifier.to_string(), name: name.to_string(), nodes }
+    }
+
+    pub fn wet_dry(identifier: &str, name: &str, node: GraphNode) -> GraphNode {
+        GraphNode::WetDry { identifier: identifier.to_string(), name: name.to_string(), node: Box::new(node) }
+    }
+
+    fn identifier(&self) -> (&str, &str) {
+        match self {
+            GraphNode::Algorithm { identifier, name, .. } | GraphNode::Serial { identifier, name, .. }
+            | GraphNode::Parallel { identifier, name, .. } | GraphNode::WetDry { identifier, name, .. } => (identifier, name),
+        }
+    }
+
+    // Checks what bind relies on, so building the graph's set can't fail
+    fn validate(&self) -> Result<(), GraphError> {
+        let (identifier, name) = self.identifier();
+        if let Some(s) = [identifier, name].into_iter().find(|s| s.contains('\0')) {
+            return Err(GraphError::NullByte(s.to_string()));
+        }
+        let (nodes, max) = match self {
+            GraphNode::Algorithm { .. } => return Ok(()),
+            GraphNode::Serial { nodes, .. } => (nodes.as_slice(), MAX_SERIAL_NODES),
+            GraphNode::Parallel { nodes, .. } => (nodes.as_slice(), MAX_PARALLEL_NODES),
+            GraphNode::WetDry { node, .. } => (std::slice::from_ref(node.as_ref()), 1),
+        };
+        if nodes.len() > max {
+            return Err(GraphError::TooManyNodes { identifier: identifier.to_string(), count: nodes.len(), max });
+        }
+        // The identifiers of the set's children in the order bind adds them
+        let mut children = Vec::new();
+        for node in nodes {
+            let child = node.identifier().0;
+            match self {
+                GraphNode::Parallel { .. } => children.push(format!("{}_level", child)),
+                GraphNode::WetDry { .. } => children.push("mix".to_string()),
+                _ => {}
+            }
+            children.push(child.to_string());
+        }
+        for (i, child) in children.iter().enumerate() {
+            if children[..i].contains(child) {
+                return Err(GraphError::DuplicateIdentifier { parent: identifier.to_string(), identifier: child.clone() });
+            }
+        }
+        nodes.iter().try_for_each(GraphNode::validate)
+    }
+
+    // Binds the algorithms. Returns the node's set, named identifier, and its render side.
+    fn bind(self, identifier: &str, name: &str, notifier: ParamNotifier) -> (AlgoParamSet, BoundNode) {
+        match self {
+            GraphNode::Algorithm { algo, .. } => {
+                let (set, algo) = algo.bind(identifier, name, notifier);
+                (set, BoundNode::Algorithm(algo))
+            }
+            GraphNode::Serial { nodes, .. } => {
+                let mut set = AlgoParamSet::new(identifier, name);
+                let nodes = nodes.into_iter().map(|node| bind_child(&mut set, node, &notifier)).collect();
+                (set, BoundNode::Serial { nodes, buffer: Vec::new() })
+            }
+            GraphNode::Parallel { nodes, .. } => {
+                let mut set = AlgoParamSet::new(identifier, name);
+                let branches = nodes.into_iter().map(|node| {
+                    let level = Arc::new(AtomicF32::new(1.0));
+                    let (child, child_name) = node.identifier();
+                    let param = AlgoParam::builder(&format!("{}_level", child))
+                        .name(&format!("{} Level", child_name))
+                        .unit(AlgoParamUnit::LINEARGAIN)
+                        .range(0.0, 2.0)
+                        .default(1.0)
+                        .bind(&level)
+                        .build()
+                        .expect("Identifiers are checked in Graph::new");
+                    set.add(AlgoParamNode::Param(param)).expect("Node counts are checked in Graph::new");
+                    Branch { node: bind_child(&mut set, node, &notifier), level, current: 1.0 }
+                }).collect();
+                (set, BoundNode::Parallel { branches, buffer: Vec::new() })
+            }
+            GraphNode::WetDry { node, .. } => {
+                let mut set = AlgoParamSet::new(identifier, name);
+                let mix = Arc::new(AtomicF32::new(1.0));
+                let param = AlgoParam::builder("mix")
+                    .name("Mix")
+                    .unit(AlgoParamUnit::PERCENT)
+                    .range(0.0, 100.0)
+                    .default(100.0)
+                    .bind_with(&mix, ValueTransform::PERCENT_TO_FRACTION)
+                    .build()
+                    .expect("The mix parameter is valid");
+                set.add(AlgoParamNode::Param(param)).expect("A new set has room");
+                let node = Box::new(bind_child(&mut set, *node, &notifier));
+                (set, BoundNode::WetDry { node, mix, current: 1.0, dry: Vec::new() })
+            }
+        }
+    }
+}
+
+fn bind_child(parent: &mut AlgoParamSet, node: GraphNode, notifier: &ParamNotifier) -> BoundNode {
+    let (identifier, name) = node.identifier();
+    let (identifier, name) = (identifier.to_string(), name.to_string());
+    let (set, bound) = node.bind(&identifier, &name, notifier.scoped(&identifier));
+    parent.add(AlgoParamNode::ParamSet(set)).expect("Node counts are checked in Graph::new");
+    bound
+}
+
+type Buffer = Vec<[f32; GRAPH_CHUNK]>;
+
+fn new_buffer() -> Buffer {
+    vec![[0.0; GRAPH_CHUNK]; MAX_SPLIT_CHANNELS]
+}
+
+// The first len samples of count channels of buffer
+fn channels(buffer: &mut Buffer, count: usize, len: usize) -> [&mut [f32]; MAX_SPLIT_CHANNELS] {
+    let mut channels: [&mut [f32]; MAX_SPLIT_CHANNELS] = Default::default();
+    for (channel, samples) in channels.iter_mut().zip(buffer.iter_mut()).take(count) {
+        *channel = &mut samples[..len];
+    }
+    channels
+}
+
+// Gain moving from current to the parameter value over the chunk, so level changes don't click
+fn ramp(current: &mut f32, target: &AtomicF32, len: usize) -> impl Fn(usize) -> f32 {
+    let (from, to) = (*current, target.load(Ordering::Relaxed));
+    *current = to;
+    move |i| from + (to - from) * (i + 1) as f32 / len as f32
+}
+
+struct Branch {
+    node: BoundNode,
+    level: Arc<AtomicF32>,
+    current: f32,
+}
+
+enum BoundNode {
+    Algorithm(Box<dyn BoundAlgorithm>),
+    Serial { nodes: Vec<BoundNode>, buffer: Buffer },
+    Parallel { branches: Vec<Branch>, buffer: Buffer },
+    WetDry { node: Box<BoundNode>, mix: Arc<AtomicF32>, current: f32, dry: Buffer },
+}
+
+impl BoundNode {
+    fn init(&mut self, fs: i32) {
+        match self {
+            BoundNode::Algorithm(algo) => algo.init(fs),
+            BoundNode::Serial { nodes, buffer } => {
+                *buffer = new_buffer();
+                nodes.iter_mut().for_each(|node| node.init(fs));
+            }
+            BoundNode::Parallel { branches, buffer } => {
+                *buffer = new_buffer();
+                branches.iter_mut().for_each(|branch| branch.node.init(fs));
+            }
+            BoundNode::WetDry { node, dry, .. } => {
+                *dry = new_buffer();
+                node.init(fs);
+            }
+        }
+    }
+
+    fn send_midi(&self, data: &[u8], timestamp: u64) {
+        match self {
+            BoundNode::Algorithm(algo) => algo.send_midi(data, timestamp),
+            BoundNode::Serial { nodes, .. } => nodes.iter().for_each(|node| node.send_midi(data, timestamp)),
+            BoundNode::Parallel { branches, .. } => branches.iter().for_each(|branch| branch.node.send_midi(data, timestamp)),
+            BoundNode::WetDry { node, .. } => node.send_midi(data, timestamp),
+        }
+    }
+
+    // At most MAX_SPLIT_CHANNELS channels of at most GRAPH_CHUNK samples
+    fn process(&mut self, outputs: &mut [&mut [f32]], inputs: &[&[f32]]) {
+        let len = outputs.iter().map(|o| o.len()).chain(inputs.iter().map(|i| i.len())).min().unwrap_or(0);
+        match self {
+            BoundNode::Algorithm(algo) => algo.process(outputs, inputs),
+            BoundNode::Serial { nodes, buffer } => {
+                let Some((first, rest)) = nodes.split_first_mut() else {
+                    for (c, output) in outputs.iter_mut().enumerate() {
+                        match inputs.get(c) {
+                            Some(input) => output[..len].copy_from_slice(&input[..len]),
+                            None => output.fill(0.0),
+                        }
+                    }
+                    return;
+                };
+                first.process(outputs, inputs);
+                for node in rest {
+                    for (samples, output) in buffer.iter_mut().zip(outputs.iter()) {
+                        samples[..len].copy_from_slice(&output[..len]);
+                    }
+                    let mut previous: [&[f32]; MAX_SPLIT_CHANNELS] = Default::default();
+                    for (channel, samples) in previous.iter_mut().zip(buffer.iter()) {
+                        *channel = &samples[..len];
+                    }
+                    node.process(outputs, &previous[..outputs.len()]);
+                }
+            }
+            BoundNode::Parallel { branches, buffer } => {
+                outputs.iter_mut().for_each(|output| output.fill(0.0));
+                for branch in branches.iter_mut() {
+                    let mut branch_out = channels(buffer, outputs.len(), len);
+                    branch.node.process(&mut branch_out[..outputs.len()], inputs);
+                    let gain = ramp(&mut branch.current, &branch.level, len);
+                    for (output, samples) in outputs.iter_mut().zip(branch_out.iter()) {
+                        for (i, (o, s)) in output.iter_mut().zip(samples.iter()).enumerate() {
+                            *o += gain(i) * s;
+                        }
+                    }
+                }
+            }
+            BoundNode::WetDry { node, mix, current, dry } => {
+                for (c, samples) in dry.iter_mut().take(outputs.len()).enumerate() {
+                    match inputs.get(c) {
+                        Some(input) => samples[..len].copy_from_slice(&input[..len]),
+                        None => samples[..len].fill(0.0),
+                    }
+                }
+                node.process(outputs, inputs);
+                let wet = ramp(current, mix, len);
+                for (output, samples) in outputs.iter_mut().zip(dry.iter()) {
+                    for (i, (o, d)) in output.iter_mut().zip(samples.iter()).enumerate() {
+                        *o = d + wet(i) * (*o - d);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Render side, only locked by the audio thread (and init)
+struct GraphRender {
+    root: Option<BoundNode>,
+    ready: bool,    // buffers allocated
+}
+
+pub struct Graph {
+    root: Mutex<Option<GraphNode>>,
+    notifier: Option<ParamNotifier>,
+    render: Mutex<GraphRender>,
+}
+
+impl Graph {
+    // Fails if a node has more nodes than fit into its set, two children of a set share an identifier, or an
+    // identifier or name contains a null byte
+    pub fn new(root: GraphNode) -> Result<Graph, GraphError> {
+        root.validate()?;
+        Ok(Graph { root: Mutex::new(Some(root)), notifier: None, render: Mutex::new(GraphRender { root: None, ready: false }) })
+    }
+}
+
+impl Algorithm for Graph {
+    type Params = ();
+
+    fn init(&mut self, fs: i32) {
+        let render = self.render.get_mut().unwrap_or_else(|e| e.into_inner());
+        if let Some(root) = render.root.as_mut() {
+            root.init(fs);
+            render.ready = true;
+        }
+    }
+
+    fn set_notifier(&mut self, notifier: ParamNotifier) {
+        self.notifier = Some(notifier);
+    }
+
+    // The nodes can only be bound once, later calls return an empty set
+    fn get_parameters(&self, basename: &str, displayname: &str) -> (AlgoParamSet, ()) {
+        let Some(root) = self.root.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+            return (AlgoParamSet::new(basename, displayname), ());
+        };
+        // Changes reported by nodes of a graph that isn't part of a module go nowhere
+        let notifier = self.notifier.clone().unwrap_or_else(|| ParamNotifier::new(ChangeChannel::new()));
+        let (set, bound) = root.bind(basename, displayname, notifier);
+        self.render.lock().unwrap_or_else(|e| e.into_inner()).root = Some(bound);
+        (set, ())
+    }
+
+    fn process(&self, _params: &(), outputs: &mut [&mut [f32]], inputs: &[&[f32]]) {
+        let (n_out, n_in) = (outputs.len().min(MAX_SPLIT_CHANNELS), inputs.len().min(MAX_SPLIT_CHANNELS));
+        outputs[n_out..].iter_mut().for_each(|output| output.fill(0.0));
+        let mut render = match self.render.try_lock() {
+            Ok(render) if render.ready => render,
+            _ => {
+                outputs.iter_mut().for_each(|output| output.fill(0.0));
+                return;
+            }
+        };
+        let Some(root) = render.root.as_mut() else { return };
+        let len = outputs.iter().map(|o| o.len()).chain(inputs.iter().map(|i| i.len())).min().unwrap_or(0);
+        let mut start = 0;
+        while start < len {
+            let end = (start + GRAPH_CHUNK).min(len);
+            let (mut sub_out, sub_in) = sub_block(outputs, inputs, start, end);
+            root.process(&mut sub_out[..n_out], &sub_in[..n_in]);
+            start = end;
+        }
+    }
+
+    fn send_midi(&self, data: &[u8], timestamp: u64) {
+        if let Ok(render) = self.render.try_lock() && let Some(root) = render.root.as_ref() {
+            root.send_midi(data, timestamp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support::Gain, SoundModule};
+
+    fn scale(gain: f32) -> Gain {
+        Gain::new().range(gain, 4.0)
+    }
+
+    #[test]
+    fn test_graph() {
+        let graph = Graph::new(GraphNode::serial("chain", "Chain", vec![
+            GraphNode::algorithm("pre", "Pre", scale(2.0)),
+            GraphNode::parallel("split", "Split", vec![
+                GraphNode::algorithm("a", "A", scale(1.0)),
+                GraphNode::algorithm("b", "B", scale(3.0)),
+            ]),
+            GraphNode::wet_dry("fx", "FX", GraphNode::algorithm("post", "Post", scale(0.5))),
+        ])).unwrap();
+        let (control, mut render) = SoundModule::new(graph).split();
+        let tree = control.param();
+        for keypath in ["pre.gain", "split.a_level", "split.a.gain", "split.b.gain", "fx.mix", "fx.post.gain"] {
+            assert!(tree.address_of(keypath).is_some(), "{keypath}");
+        }
+        render.init(48000);
+
+        // (2 * 1 + 2 * 3) * 0.5
+        let mut out = [[0.0; 300]; 2];
+        let [left, right] = &mut out;
+        render.run(&mut [left, right], &[&[1.0; 300], &[1.0; 300]]);
+        assert_eq!((out[0][299], out[1][299]), (4.0, 4.0));
+
+        control.set_parameter(tree.address_of("split.b_level").unwrap(), 0.0).unwrap();
+        control.set_parameter(tree.address_of("fx.mix").unwrap(), 50.0).unwrap();
+        let [left, right] = &mut out;
+        render.run(&mut [left, right], &[&[1.0; 300], &[1.0; 300]]);
+        // Ramped over the first chunk, then 2 * 0.5 + (2 * 0.5) * 0.5
+        assert!(out[0][0] > 3.0);
+        assert_eq!(out[0][299], 1.5);
+    }
+
+    #[test]
+    fn test_graph_limits() {
+        let branches = (0..128).map(|i| GraphNode::algorithm(&format!("b{i}"), "B", scale(1.0))).collect();
+        let nested = GraphNode::serial("chain", "Chain", vec![GraphNode::parallel("split", "Split", branches)]);
+        assert_eq!(Graph::new(nested).err(), Some(GraphError::TooManyNodes { identifier: "split".to_string(), count: 128, max: 127 }));
+        let invalid = GraphNode::wet_dry("fx", "FX", GraphNode::algorithm("a\0", "A", scale(1.0)));
+        assert_eq!(Graph::new(invalid).err(), Some(GraphError::NullByte("a\0".to_string())));
+
+        let twice = GraphNode::serial("chain", "Chain", vec![GraphNode::algorithm("eq", "EQ", scale(1.0)), GraphNode::algorithm("eq", "EQ", scale(1.0))]);
+        assert_eq!(Graph::new(twice).err(), Some(GraphError::DuplicateIdentifier { parent: "chain".to_string(), identifier: "eq".to_string() }));
+        let level = GraphNode::parallel("split", "Split", vec![GraphNode::algorithm("a", "A", scale(1.0)), GraphNode::algorithm("a_level", "A Level", scale(1.0))]);
+        assert_eq!(Graph::new(level).err(), Some(GraphError::DuplicateIdentifier { parent: "split".to_string(), identifier: "a_level".to_string() }));
+        let mix = GraphNode::serial("chain", "Chain", vec![GraphNode::wet_dry("fx", "FX", GraphNode::algorithm("mix", "Mix", scale(1.0)))]);
+        assert_eq!(Graph::new(mix).err(), Some(GraphError::DuplicateIdentifier { parent: "fx".to_string(), identifier: "mix".to_string() }));
+    }
+}
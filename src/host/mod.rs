@@ -0,0 +1,318 @@
+use crate::{algoparam::{AlgoParamSet, SetParameterError, KEY_NOT_FOUND}, event::ParamEvent, descriptor::AlgoDescriptor, registry, Algorithm, ControlHandle, DynAlgorithm, RenderHandle, SoundModule};
+use std::sync::Arc;
+
+// Safe Rust front end for loading and driving SoundModules, for test harnesses and tools that would
+// otherwise go through the C functions.
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HostError {
+    UnknownAlgorithm,
+    UnknownParameter,
+    ReadOnlyParameter,
+    ValueOutOfRange,
+    QueueFull,
+    BufferSizeMismatch,
+    Faulted,
+}
+
+impl std::fmt::Display for HostError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HostError::UnknownAlgorithm => write!(f, "No algorithm with that name is registered"),
+            HostError::UnknownParameter => write!(f, "No parameter at that keypath or address"),
+            HostError::ReadOnlyParameter => write!(f, "The parameter is read-only"),
+            HostError::ValueOutOfRange => write!(f, "The value is outside the parameter range"),
+            HostError::QueueFull => write!(f, "Too many parameter events are pending"),
+            HostError::BufferSizeMismatch => write!(f, "Input and output buffers differ in length"),
+            HostError::Faulted => write!(f, "The module has faulted"),
+        }
+    }
+}
+
+impl std::error::Error for HostError {}
+
+impl From<SetParameterError> for HostError {
+    fn from(e: SetParameterError) -> HostError {
+        match e {
+            SetParameterError::UnknownAddress => HostError::UnknownParameter,
+            SetParameterError::ReadOnly => HostError::ReadOnlyParameter,
+            SetParameterError::OutOfRange { .. } => HostError::ValueOutOfRange,
+            SetParameterError::QueueFull => HostError::QueueFull,
+            SetParameterError::Faulted => HostError::Faulted,
+        }
+    }
+}
+
+// Creates instances from the algorithm registry, initialized at the host sample rate
+pub struct Host {
+    fs: i32,
+}
+
+impl Host {
+    pub fn new(fs: i32) -> Host {
+        Host { fs }
+    }
+
+    pub fn sample_rate(&self) -> i32 {
+        self.fs
+    }
+
+    pub fn algorithms(&self) -> Vec<String> {
+        registry::algorithm_names()
+    }
+
+    pub fn describe(&self, name: &str) -> Option<AlgoDescriptor> {
+        registry::algorithm_descriptor(name)
+    }
+
+    pub fn create(&self, name: &str) -> Result<Instance, HostError> {
+        let algo = registry::create_algorithm(name).ok_or(HostError::UnknownAlgorithm)?;
+        Ok(self.instantiate_dyn(algo))
+    }
+
+    pub fn instantiate<A: Algorithm + 'static>(&self, algo: A) -> Instance {
+        self.instantiate_dyn(Box::new(algo))
+    }
+
+    pub fn instantiate_dyn(&self, algo: Box<dyn DynAlgorithm>) -> Instance {
+        let mut instance = Instance::from_dyn(algo);
+        instance.init(self.fs);
+        instance
+    }
+}
+
+// An owned SoundModule. Parameters are addressed by keypath relative to the root set, e.g. "filter.cutoff".
+// Harnesses that render on a separate thread can split the instance into its control and render handles.
+pub struct Instance {
+    module: SoundModule,
+}
+
+impl Instance {
+    pub fn new<A: Algorithm + 'static>(algo: A) -> Instance {
+        Instance { module: SoundModule::new(algo) }
+    }
+
+    pub fn from_dyn(algo: Box<dyn DynAlgorithm>) -> Instance {
+        Instance { module: SoundModule::from_dyn(algo) }
+    }
+
+    pub fn init(&mut self, fs: i32) {
+        self.module.render.init(fs);
+    }
+
+    pub fn into_handles(self) -> (ControlHandle, RenderHandle) {
+        self.module.split()
+    }
+
+    pub fn descriptor(&self) -> &AlgoDescriptor {
+        &self.module.control.descriptor
+    }
+
+    // The current parameter tree. Algorithms may replace parts of it, see tree::ParamTree.
+    pub fn parameters(&self) -> Arc<AlgoParamSet> {
+        self.module.control.param()
+    }
+
+    pub fn is_faulted(&self) -> bool {
+        self.module.is_faulted()
+    }
+
+    pub fn address(&self, keypath: &str) -> Option<u64> {
+        self.module.control.param().address_of(keypath)
+    }
+
+    // Returns the value that was applied after clamping and rounding
+    pub fn set_parameter(&self, keypath: &str, value: f32) -> Result<f32, HostError> {
+        let address = self.address(keypath).ok_or(HostError::UnknownParameter)?;
+        self.set_parameter_at(address, value)
+    }
+
+    pub fn get_parameter(&self, keypath: &str) -> Result<f32, HostError> {
+        let address = self.address(keypath).ok_or(HostError::UnknownParameter)?;
+        self.get_parameter_at(address)
+    }
+
+    pub fn set_parameter_at(&self, address: u64, value: f32) -> Result<f32, HostError> {
+        if address == KEY_NOT_FOUND {
+            return Err(HostError::UnknownParameter);
+        }
+        let value = self.module.control.set_parameter(address, value)?;
+        self.check_fault()?;
+        Ok(value)
+    }
+
+    // Sets all values at the start of the next processed block, see ControlHandle::begin_batch
+    pub fn set_parameters(&self, values: &[(&str, f32)]) -> Result<(), HostError> {
+        let control = &self.module.control;
+        let mut batch = control.begin_batch();
+        for (keypath, value) in values {
+            let address = self.address(keypath).ok_or(HostError::UnknownParameter)?;
+            control.stage_parameter(&mut batch, address, *value)?;
+        }
+        Ok(control.commit_batch(batch)?)
+    }
+
+    // Changes the parameter at sample_time (counted from the first processed sample) during process,
+    // moving there over ramp samples
+    pub fn schedule_parameter(&self, keypath: &str, value: f32, sample_time: u64, ramp: u32) -> Result<(), HostError> {
+        let address = self.address(keypath).ok_or(HostError::UnknownParameter)?;
+        Ok(self.module.control.schedule_parameter(ParamEvent { address, value, sample_time, ramp })?)
+    }
+
+    pub fn get_parameter_at(&self, address: u64) -> Result<f32, HostError> {
+        if address == KEY_NOT_FOUND {
+            return Err(HostError::UnknownParameter);
+        }
+        let value = self.module.control.get_parameter(address).map_err(|_| HostError::UnknownParameter)?;
+        self.check_fault().map(|_| value)
+    }
+
+    pub fn send_midi(&mut self, data: &[u8], timestamp: u64) -> Result<(), HostError> {
+        self.module.render.send_midi(data, timestamp);
+        self.check_fault()
+    }
+
+    // Renders one block with any number of channels. All buffers must have the same length.
+    pub fn process(&mut self, outputs: &mut [&mut [f32]], inputs: &[&[f32]]) -> Result<(), HostError> {
+        let len = outputs.first().map(|o| o.len()).or(inputs.first().map(|i| i.len())).unwrap_or(0);
+        if outputs.iter().map(|o| o.len()).chain(inputs.iter().map(|i| i.len())).any(|l| l != len) {
+            return Err(HostError::BufferSizeMismatch);
+        }
+        self.module.render.run(outputs, inputs);
+        self.check_fault()
+    }
+
+    fn check_fault(&self) -> Result<(), HostError> {
+        if self.module.is_faulted() {
+            Err(HostError::Faulted)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support::Gain, MAX_SPLIT_CHANNELS};
+
+    // The parameters are in a "stage" set, processing panics at a gain of 1.5
+    fn gain() -> Gain {
+        Gain::new().in_set("stage").panic_at(1.5).with_trip()
+    }
+
+    #[test]
+    fn test_instance() {
+        let host = Host::new(48000);
+        let mut instance = host.instantiate(gain());
+        assert_eq!(instance.address("stage.gain"), Some(0x0000_ffff_ffff_ffff));
+        assert_eq!(instance.address("stage"), None);
+        assert_eq!(instance.set_parameter("stage.missing", 0.0), Err(HostError::UnknownParameter));
+
+        assert_eq!(instance.set_parameter("stage.gain", 5.0), Ok(2.0));
+        assert_eq!(instance.set_parameter("stage.gain", 0.5), Ok(0.5));
+        assert_eq!(instance.get_parameter("stage.gain"), Ok(0.5));
+
+        let input = [1.0f32; 4];
+        let (mut left, mut right) = ([0.0f32; 4], [0.0f32; 4]);
+        instance.process(&mut [&mut left, &mut right], &[&input, &input]).unwrap();
+        assert_eq!(left, [0.5; 4]);
+        assert_eq!(instance.process(&mut [&mut left, &mut right[..2]], &[&input, &input]), Err(HostError::BufferSizeMismatch));
+        // Any number of channels
+        let mut center = [0.0f32; 4];
+        instance.process(&mut [&mut center], &[&input]).unwrap();
+        assert_eq!(center, [0.5; 4]);
+
+        // The algorithm panics above 1.5, after which the instance outputs silence
+        instance.set_parameter("stage.gain", 2.0).unwrap();
+        assert_eq!(instance.process(&mut [&mut left, &mut right], &[&input, &input]), Err(HostError::Faulted));
+        assert_eq!(left, [0.0; 4]);
+    }
+
+    #[test]
+    fn test_faulting_setter() {
+        let instance = Host::new(48000).instantiate(gain());
+        assert_eq!(instance.set_parameter("stage.trip", 1.0), Err(HostError::Faulted));
+        assert!(instance.is_faulted());
+
+        let (control, _render) = Host::new(48000).instantiate(gain()).into_handles();
+        let address = control.param().address_of("stage.trip").unwrap();
+        assert_eq!(control.set_parameter(address, 1.0), Err(SetParameterError::Faulted));
+    }
+
+    #[test]
+    fn test_split_handles() {
+        let (control, mut render) = Host::new(48000).instantiate(gain()).into_handles();
+        let address = control.param().address_of("stage.gain").unwrap();
+        let input = [1.0f32; 64];
+        let (mut left, mut right) = ([0.0f32; 64], [0.0f32; 64]);
+        let audio = std::thread::spawn(move || {
+            let (mut left, mut right) = ([0.0f32; 64], [0.0f32; 64]);
+            for _ in 0..100 {
+                render.run(&mut [&mut left, &mut right], &[&input, &input]);
+                // The render thread may get ahead of all the sets, or run between them
+                assert!([1.0, 0.0, 0.25].contains(&left[0]));
+            }
+            render
+        });
+        for i in 0..100 {
+            control.set_parameter(address, (i % 2) as f32 * 0.25).unwrap();
+        }
+        control.set_parameter(address, 0.25).unwrap();
+        let mut render = audio.join().unwrap();
+        render.run(&mut [&mut left, &mut right], &[&input, &input]);
+        assert_eq!(left[0], 0.25);
+        assert!(!control.is_faulted());
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut instance = Host::new(48000).instantiate(gain());
+        let input = [1.0f32; 16];
+        let (mut left, mut right) = ([0.0f32; 16], [0.0f32; 16]);
+
+        assert_eq!(instance.set_parameters(&[("stage.gain", 0.5), ("stage.missing", 0.0)]), Err(HostError::UnknownParameter));
+        instance.set_parameters(&[("stage.gain", 0.5), ("stage.gain", 0.75)]).unwrap();
+        assert_eq!(instance.get_parameter("stage.gain"), Ok(1.0));
+        instance.process(&mut [&mut left, &mut right], &[&input, &input]).unwrap();
+        assert_eq!((left[0], left[15]), (0.75, 0.75));
+        assert_eq!(instance.get_parameter("stage.gain"), Ok(0.75));
+    }
+
+    #[test]
+    fn test_scheduled_parameters() {
+        let mut instance = Host::new(48000).instantiate(gain());
+        let input = [1.0f32; 128];
+        let (mut left, mut right) = ([0.0f32; 128], [0.0f32; 128]);
+
+        // A jump in the middle of the block, and one that is due in the next block
+        instance.schedule_parameter("stage.gain", 0.5, 10, 0).unwrap();
+        instance.schedule_parameter("stage.gain", 0.25, 130, 0).unwrap();
+        instance.process(&mut [&mut left, &mut right], &[&input, &input]).unwrap();
+        assert_eq!((left[9], left[10], left[127]), (1.0, 0.5, 0.5));
+        instance.process(&mut [&mut left, &mut right], &[&input, &input]).unwrap();
+        assert_eq!((left[1], left[2]), (0.5, 0.25));
+
+        // Ramp from 0.25 to 1.25 over 64 samples, in steps of 32
+        instance.schedule_parameter("stage.gain", 1.25, 0, 64).unwrap();
+        instance.process(&mut [&mut left, &mut right], &[&input, &input]).unwrap();
+        assert_eq!((left[0], left[31], left[32], left[63], left[64]), (0.25, 0.25, 0.75, 0.75, 1.25));
+        assert_eq!(instance.get_parameter("stage.gain"), Ok(1.25));
+        assert_eq!(instance.module.control.sample_time(), 384);
+    }
+
+    #[test]
+    fn test_scheduled_parameters_wide_block() {
+        // More channels than MAX_SPLIT_CHANNELS, so events inside the block apply at its start
+        let (control, mut render) = Host::new(48000).instantiate(gain()).into_handles();
+        let address = control.param().address_of("stage.gain").unwrap();
+        let input = [1.0f32; 16];
+        let mut outputs = [[0.0f32; 16]; MAX_SPLIT_CHANNELS + 1];
+        control.schedule_parameter(ParamEvent { address, value: 0.5, sample_time: 10, ramp: 0 }).unwrap();
+        control.schedule_parameter(ParamEvent { address, value: 0.25, sample_time: 16, ramp: 0 }).unwrap();
+        render.run(&mut outputs.each_mut().map(|o| o.as_mut_slice()), &[&input[..]; MAX_SPLIT_CHANNELS + 1]);
+        assert!(outputs.iter().all(|o| o.iter().all(|&s| s == 0.5)));
+        render.run(&mut outputs.each_mut().map(|o| o.as_mut_slice()), &[&input[..]; MAX_SPLIT_CHANNELS + 1]);
+        assert_eq!((outputs[8][0], control.sample_time()), (0.25, 32));
+    }
+}
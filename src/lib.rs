@@ -1,77 +1,636 @@
-use algoparam::{AlgoParamSet};
-use core::{ffi::c_void};
-use std::{any::Any, slice};
+use algoparam::{AlgoParamSet, OutOfRangeError, SetParameterError};
+use core::{ffi::{c_char, c_void, CStr}};
+use descriptor::{AlgoCDescriptor, AlgoDescriptor};
+use event::{BatchChannel, EventQueue, EventScheduler, ParamBatch, ParamEvent, QueuedEvent};
+use notify::{AlgoCParamChange, CChangeCallback, ChangeCallback, ChangeChannel, ParamChange, ParamNotifier};
+use std::{slice, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex}};
+use tree::ParamTree;
+
+// Lets the code generated by soundmodule-derive refer to ::soundmodule from inside this crate
+extern crate self as soundmodule;
 
 pub mod algoparam;
+pub mod descriptor;
+pub mod event;
+pub mod fault;
+pub mod format;
+pub mod graph;
+pub mod host;
+pub mod notify;
+pub mod registry;
+pub mod slot;
+pub mod submodule;
+pub mod taper;
+pub mod tree;
 pub mod util;
+#[cfg(test)]
+mod test_support;
+
+#[cfg(feature = "derive")]
+pub use soundmodule_derive::Params;
 pub trait Algorithm : Send + Sync {
+    // Storage shared between the parameter setters and process
+    type Params: Send + 'static;
     // Returns an AlgoParamSet with basename as name. Each algorithm parameter uses self_ref for control.
-    // Submodules must be instantiated as Rc<RefCell<>> and the corresponding parameters inserted in the tree by this method.
+    // Child algorithms are kept in submodule::Submodule, which mounts their parameters in the tree.
+    fn init(&mut self, fs: i32);
+    // Returns the parameter set and the associated storage for using with the setter.
+    // The setters run on the control thread (or on the audio thread between sub-blocks for scheduled
+    // events) while process runs on the audio thread, so the storage they share must be thread safe
+    // (util::AtomicF32, util::Smooth ...) and the setters must not block.
+    fn get_parameters(&self, basename: &str, displayname: &str) -> (AlgoParamSet, Self::Params);
+    fn process(&self, params: &Self::Params, outputs: &mut [&mut [f32]], inputs: &[&[f32]]);
+    fn send_midi(&self, data: &[u8], timestamp: u64);
+    // Called before get_parameters. Algorithms that change their own parameters keep the notifier to tell the host,
+    // also to rebuild parts of their tree later (ParamNotifier::replace_subtree).
+    fn set_notifier(&mut self, _notifier: ParamNotifier) {}
+    // Plugin identity (name, vendor, version, category, unique id, features) used by host wrappers
+    fn descriptor(&self) -> AlgoDescriptor {
+        AlgoDescriptor::default()
+    }
+}
+
+// Type-erased forms of Algorithm, for the registry and the FFI layer where the parameter type is unknown.
+// DynAlgorithm is an algorithm that has not created its parameters yet; binding it yields the parameter tree and
+// a BoundAlgorithm that keeps the typed parameters next to the algorithm, so process never has to downcast.
+
+pub trait DynAlgorithm : Send + Sync {
+    fn descriptor(&self) -> AlgoDescriptor;
+    fn bind(self: Box<Self>, basename: &str, displayname: &str, notifier: ParamNotifier) -> (AlgoParamSet, Box<dyn BoundAlgorithm>);
+}
+
+pub trait BoundAlgorithm : Send {
     fn init(&mut self, fs: i32);
-    // Returns the parameter set and the associated storage for using with the setter
-    fn get_parameters(&self, basename: &str, displayname: &str) -> (AlgoParamSet, Box<dyn Any>);
-    fn process(&self, parameter_zone: &Box<dyn Any>, outputs: &mut [&mut [f32]], inputs: &[&[f32]]);
+    fn process(&self, outputs: &mut [&mut [f32]], inputs: &[&[f32]]);
     fn send_midi(&self, data: &[u8], timestamp: u64);
 }
 
+pub struct Bound<A: Algorithm> {
+    pub algo: A,
+    pub params: A::Params,
+}
+
+impl<A: Algorithm + 'static> DynAlgorithm for A {
+    fn descriptor(&self) -> AlgoDescriptor {
+        Algorithm::descriptor(self)
+    }
+
+    fn bind(mut self: Box<Self>, basename: &str, displayname: &str, notifier: ParamNotifier) -> (AlgoParamSet, Box<dyn BoundAlgorithm>) {
+        self.set_notifier(notifier);
+        let (param, params) = self.get_parameters(basename, displayname);
+        (param, Box::new(Bound { algo: *self, params }))
+    }
+}
+
+impl<A: Algorithm> BoundAlgorithm for Bound<A> {
+    fn init(&mut self, fs: i32) {
+        self.algo.init(fs);
+    }
+
+    fn process(&self, outputs: &mut [&mut [f32]], inputs: &[&[f32]]) {
+        self.algo.process(&self.params, outputs, inputs);
+    }
+
+    fn send_midi(&self, data: &[u8], timestamp: u64) {
+        self.algo.send_midi(data, timestamp);
+    }
+}
+
+// Thread contract
+//
+// A SoundModule is split in two halves that never touch each other's data:
+// - ControlHandle owns the parameter tree and the descriptor. It is used from the UI/automation thread(s).
+//   Parameter changes reach the audio thread through the (atomic) storage the setters write to.
+//   All control methods take &self, so they may also be called concurrently.
+// - RenderHandle owns the bound algorithm (algorithm and parameter zone). It is used from the audio thread only:
+//   init, run and send_midi must not overlap each other.
+// Both halves share the fault flag and the render position, which may be read from any thread, and the
+// parameter tree: scheduled parameter events go through a lock-free queue and run their setters in run.
+// When the algorithm replaces part of the tree (tree::ParamTree), the render side switches over at the
+// start of the next block. Events and batch values scheduled against the replaced tree are dropped then.
+
+struct ModuleState {
+    // Set when the algorithm has panicked. A faulted module only outputs silence.
+    faulted: AtomicBool,
+    // Samples rendered so far, the time base for scheduled parameter events
+    sample_time: AtomicU64,
+}
+
+impl ModuleState {
+    fn is_faulted(&self) -> bool {
+        self.faulted.load(Ordering::Acquire)
+    }
+
+    // Runs f, returning None and putting the module in the faulted state if it panics
+    fn contain<R>(&self, context: &str, f: impl FnOnce() -> R) -> Option<R> {
+        let result = fault::catch(context, f);
+        if result.is_none() {
+            self.faulted.store(true, Ordering::Release);
+        }
+        result
+    }
+}
+
+pub struct ControlHandle {
+    tree: Arc<ParamTree>,
+    // The tree last handed out by soundmodule_get_params, kept alive until the next call
+    exported: Mutex<Option<Arc<AlgoParamSet>>>,
+    pub descriptor: AlgoDescriptor,
+    events: Arc<EventQueue>,
+    batches: Arc<BatchChannel>,
+    changes: Arc<ChangeChannel>,
+    state: Arc<ModuleState>,
+}
+
+impl ControlHandle {
+    pub fn is_faulted(&self) -> bool {
+        self.state.is_faulted()
+    }
+
+    // The current parameter tree. Parts of it may be replaced later, see tree_generation.
+    pub fn param(&self) -> Arc<AlgoParamSet> {
+        self.tree.collect();
+        self.tree.get()
+    }
+
+    // Increments whenever the algorithm replaces part of the tree. The change is also reported as
+    // ParamChange::TreeChanged.
+    pub fn tree_generation(&self) -> u64 {
+        self.tree.generation()
+    }
+
+    // Writes to READ_ONLY parameters are refused. Returns the value that was applied.
+    // Dependents that change as a result are reported as parameter changes (see pop_change).
+    pub fn set_parameter(&self, address: u64, value: f32) -> Result<f32, SetParameterError> {
+        let changes = &self.changes;
+        let mut report = |address, value| changes.send(ParamChange::Value { address, value });
+        let param = self.param();
+        self.state.contain("set_parameter", || param.set_reporting(value, address, &mut report)).unwrap_or(Err(SetParameterError::Faulted))
+    }
+
+    pub fn get_parameter(&self, address: u64) -> Result<f32, OutOfRangeError> {
+        let param = self.param();
+        self.state.contain("get_parameter", || param.get(address)).unwrap_or(Ok(0.0))
+    }
+
+    // Queues a parameter change for the sample time given in the event, see event::ParamEvent. Doesn't lock
+    // or allocate, so it may also be called from the audio thread. The event is dropped if the tree changes
+    // before it is applied.
+    pub fn schedule_parameter(&self, event: ParamEvent) -> Result<(), SetParameterError> {
+        self.tree.read(|tree, generation| {
+            let param = tree.get_param(event.address).ok_or(SetParameterError::UnknownAddress)?;
+            if param.is_read_only() {
+                return Err(SetParameterError::ReadOnly);
+            }
+            self.events.push(QueuedEvent { event, generation }).map_err(|_| SetParameterError::QueueFull)
+        })
+    }
+
+    // Batches apply all their values at the start of the same block. They only take effect once the
+    // render side runs, until then get_parameter returns the old values.
+    pub fn begin_batch(&self) -> ParamBatch {
+        self.batches.begin()
+    }
+
+    // Adds a value to the batch, refusing unknown and read-only addresses like set_parameter. The value is
+    // dropped if the tree changes before the batch is applied.
+    pub fn stage_parameter(&self, batch: &mut ParamBatch, address: u64, value: f32) -> Result<(), SetParameterError> {
+        self.tree.read(|tree, generation| {
+            let param = tree.get_param(address).ok_or(SetParameterError::UnknownAddress)?;
+            if param.is_read_only() {
+                return Err(SetParameterError::ReadOnly);
+            }
+            batch.set(address, value, generation);
+            Ok(())
+        })
+    }
+
+    pub fn commit_batch(&self, batch: ParamBatch) -> Result<(), SetParameterError> {
+        self.batches.commit(batch).map_err(|_| SetParameterError::QueueFull)
+    }
+
+    // Next parameter change reported by the algorithm that didn't go to the change callback
+    pub fn pop_change(&self) -> Option<ParamChange> {
+        self.changes.pop()
+    }
+
+    // The callback runs on whatever thread the algorithm reports from, possibly the audio thread
+    pub fn set_change_callback(&self, callback: Option<ChangeCallback>) {
+        self.changes.set_callback(callback);
+    }
+
+    // Sample time of the next block to be rendered
+    pub fn sample_time(&self) -> u64 {
+        self.state.sample_time.load(Ordering::Acquire)
+    }
+
+    // Resets the parameter or set at address (ALGOPARAM_KEY_NOT_FOUND for all) to the declared defaults
+    pub fn reset_to_defaults(&self, address: u64) -> Result<usize, OutOfRangeError> {
+        let param = self.param();
+        self.state.contain("reset_to_defaults", || param.reset_to_defaults_at(address)).unwrap_or(Ok(0))
+    }
+
+    pub fn get_parameters(&self, addresses: &[u64], values: &mut [f32]) -> usize {
+        let param = self.param();
+        self.state.contain("get_parameters", || param.get_many(addresses, values)).unwrap_or(0)
+    }
+
+    pub fn to_normalized(&self, address: u64, value: f32) -> Option<f32> {
+        Some(self.param().get_param(address)?.to_normalized(value))
+    }
+
+    pub fn from_normalized(&self, address: u64, normalized: f32) -> Option<f32> {
+        Some(self.param().get_param(address)?.from_normalized(normalized))
+    }
+
+    pub fn format_parameter(&self, address: u64, value: f32) -> Option<String> {
+        let tree = self.param();
+        let param = tree.get_param(address)?;
+        self.state.contain("format_parameter", || param.format_value(value))
+    }
+
+    pub fn parse_parameter(&self, address: u64, text: &str) -> Option<f32> {
+        let tree = self.param();
+        let param = tree.get_param(address)?;
+        self.state.contain("parse_parameter", || param.parse_value(text)).flatten()
+    }
+}
+
+pub struct RenderHandle {
+    pub algo_state: Box<dyn BoundAlgorithm>,
+    tree: Arc<ParamTree>,
+    events: EventScheduler,
+    batches: Arc<BatchChannel>,
+    changes: Arc<ChangeChannel>,
+    state: Arc<ModuleState>,
+}
+
+// Blocks with more channels than this are not split for parameter events. Events falling inside such a
+// block apply at its start, ramps move once per block.
+pub(crate) const MAX_SPLIT_CHANNELS: usize = 8;
+
+// Samples start..end of each channel, for rendering a block in parts. Channels past MAX_SPLIT_CHANNELS are left out.
+pub(crate) fn sub_block<'a, 'b>(outputs: &'a mut [&mut [f32]], inputs: &'b [&[f32]], start: usize, end: usize)
+                        -> ([&'a mut [f32]; MAX_SPLIT_CHANNELS], [&'b [f32]; MAX_SPLIT_CHANNELS]) {
+    let mut sub_out: [&mut [f32]; MAX_SPLIT_CHANNELS] = Default::default();
+    let mut sub_in: [&[f32]; MAX_SPLIT_CHANNELS] = Default::default();
+    for (sub, channel) in sub_out.iter_mut().zip(outputs.iter_mut()) {
+        *sub = &mut channel[start..end];
+    }
+    for (sub, channel) in sub_in.iter_mut().zip(inputs.iter()) {
+        *sub = &channel[start..end];
+    }
+    (sub_out, sub_in)
+}
+
+// Renders a block, split into sub-blocks where parameter events are due
+fn render_with_events(algo: &dyn BoundAlgorithm, param: &AlgoParamSet, generation: u64, events: &mut EventScheduler,
+                        report: &mut dyn FnMut(u64, f32), outputs: &mut [&mut [f32]], inputs: &[&[f32]]) {
+    let len = outputs.iter().map(|o| o.len()).chain(inputs.iter().map(|i| i.len())).min().unwrap_or(0);
+    events.collect(generation);
+    if events.is_idle() {
+        algo.process(outputs, inputs);
+        events.skip(len);
+        return;
+    }
+    let (n_out, n_in) = (outputs.len(), inputs.len());
+    if n_out > MAX_SPLIT_CHANNELS || n_in > MAX_SPLIT_CHANNELS {
+        events.advance(param, len, false, report);
+        algo.process(outputs, inputs);
+        return;
+    }
+    let mut start = 0;
+    while start < len {
+        let end = start + events.advance(param, len - start, true, report);
+        let (mut sub_out, sub_in) = sub_block(outputs, inputs, start, end);
+        algo.process(&mut sub_out[..n_out], &sub_in[..n_in]);
+        start = end;
+    }
+}
+
+impl RenderHandle {
+    pub fn is_faulted(&self) -> bool {
+        self.state.is_faulted()
+    }
+
+    pub fn init(&mut self, fs: i32) {
+        let algo = &mut self.algo_state;
+        self.state.contain("init", || algo.init(fs));
+    }
+
+    pub fn send_midi(&self, data: &[u8], timestamp: u64) {
+        self.state.contain("send_midi", || self.algo_state.send_midi(data, timestamp));
+    }
+
+    // Renders one block, applying the scheduled parameter events that fall into it. Outputs silence once
+    // the module has faulted.
+    pub fn run(&mut self, outputs: &mut [&mut [f32]], inputs: &[&[f32]]) {
+        let start = self.events.sample_time();
+        if !self.is_faulted() {
+            let (algo, events, batches, changes) = (self.algo_state.as_ref(), &mut self.events, &self.batches, &self.changes);
+            let mut report = |address, value| changes.send(ParamChange::Value { address, value });
+            // The whole block uses the tree that is current at its start
+            self.tree.read(|param, generation| self.state.contain("process", || {
+                batches.apply(param, generation, &mut report);
+                render_with_events(algo, param, generation, events, &mut report, outputs, inputs)
+            }));
+        }
+        // Also covers a panic halfway through the block
+        if self.is_faulted() {
+            for channel in outputs.iter_mut() {
+                channel.fill(0.0);
+            }
+            let len = outputs.first().map(|o| o.len()).unwrap_or(0) as u64;
+            self.events.skip((start + len).saturating_sub(self.events.sample_time()) as usize);
+        }
+        self.state.sample_time.store(self.events.sample_time(), Ordering::Release);
+    }
+}
 
 pub struct SoundModule {
-    pub algo_state: Box<dyn Algorithm>,
-    pub param: AlgoParamSet,
-    pub parameter_zone: Box<dyn Any>,
+    pub control: ControlHandle,
+    pub render: RenderHandle,
 }
 
 impl SoundModule {
-    pub fn new(algo: Box<dyn Algorithm>) -> SoundModule {
+    pub fn new<A: Algorithm + 'static>(algo: A) -> SoundModule {
+        SoundModule::from_dyn(Box::new(algo))
+    }
 
-        let params = algo.get_parameters("root", "Root");
-        SoundModule { algo_state: algo, param: params.0, parameter_zone: params.1 }
+    pub fn from_dyn(algo: Box<dyn DynAlgorithm>) -> SoundModule {
+
+        let descriptor = algo.descriptor();
+        let changes = ChangeChannel::new();
+        let (param, bound) = algo.bind("root", "Root", ParamNotifier::new(changes.clone()));
+        let state = Arc::new(ModuleState { faulted: AtomicBool::new(false), sample_time: AtomicU64::new(0) });
+        let (tree, events, batches) = (ParamTree::new(param), event::new_event_queue(), BatchChannel::new());
+        changes.attach_tree(tree.clone());
+        let control = ControlHandle { tree: tree.clone(), exported: Mutex::new(None), descriptor, events: events.clone(), batches: batches.clone(), changes: changes.clone(), state: state.clone() };
+        // Start out in the state the parameter tree declares
+        let _ = control.reset_to_defaults(algoparam::KEY_NOT_FOUND);
+        SoundModule {
+            control,
+            render: RenderHandle { algo_state: bound, tree, events: EventScheduler::new(events), batches, changes, state },
+        }
     }
+
+    pub fn split(self) -> (ControlHandle, RenderHandle) {
+        (self.control, self.render)
+    }
+
+    pub fn is_faulted(&self) -> bool {
+        self.control.is_faulted()
+    }
+}
+
+// The C API hands out a single pointer to the SoundModule. The accessors below only ever borrow one half,
+// so a control call and a render call on different threads don't alias.
+
+/// # Safety
+/// this must be a SoundModule from soundmodule_create that hasn't been released.
+pub unsafe fn as_control<'a>(this: *mut c_void) -> &'a ControlHandle {
+    let module = this as *mut SoundModule;
+    assert!(!module.is_null(), "SoundModule pointer is NULL");
+    unsafe { &*std::ptr::addr_of!((*module).control) }
 }
 
-pub fn as_soundmodule<'a>(this: *mut c_void) -> &'a mut SoundModule {
-    let mut _mod = this as *mut SoundModule;
-    unsafe { _mod.as_mut().unwrap() } 
+/// # Safety
+/// this must be a SoundModule from soundmodule_create that hasn't been released, and no other render
+/// call may run at the same time.
+pub unsafe fn as_render<'a>(this: *mut c_void) -> &'a mut RenderHandle {
+    let module = this as *mut SoundModule;
+    assert!(!module.is_null(), "SoundModule pointer is NULL");
+    unsafe { &mut *std::ptr::addr_of_mut!((*module).render) }
 }
 
-pub fn soundmodule_init(this: *mut c_void, fs: i32) {
-    let myself = as_soundmodule(this);
-    myself.algo_state.init(fs);
+/// # Safety
+/// this must be a live SoundModule, see as_render.
+pub unsafe fn soundmodule_init(this: *mut c_void, fs: i32) {
+    unsafe { as_render(this) }.init(fs);
 }
 
 // API functions without name mangling
-pub fn soundmodule_release(this: *mut c_void) {
+
+/// # Safety
+/// this must be NULL or a SoundModule from soundmodule_create that isn't used afterwards.
+pub unsafe fn soundmodule_release(this: *mut c_void) {
     if !this.is_null() {
         unsafe {
-            drop(Box::from_raw(as_soundmodule(this) as *mut _));
+            drop(Box::from_raw(this as *mut SoundModule));
         }
     }
 }
 
-pub fn soundmodule_get_params(this: *mut c_void) -> *const c_void {
-    let myself = as_soundmodule(this);
-    let ptr = &myself.param as *const _ as *const c_void;
+/// The pointer stays valid until the next call, see soundmodule_get_tree_generation
+///
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+pub unsafe fn soundmodule_get_params(this: *mut c_void) -> *const c_void {
+    let myself = unsafe { as_control(this) };
+    let tree = myself.param();
+    let ptr = Arc::as_ptr(&tree) as *const c_void;
+    *myself.exported.lock().unwrap_or_else(|e| e.into_inner()) = Some(tree);
     ptr
 }
 
-pub fn soundmodule_send_midi(this: *mut c_void, data: *const u8, len: usize, timestamp: u64) {
-    let myself = as_soundmodule(this);
-    let box_ref = &myself.algo_state;
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+pub unsafe fn soundmodule_get_tree_generation(this: *mut c_void) -> u64 {
+    unsafe { as_control(this) }.tree_generation()
+}
+
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+pub unsafe fn soundmodule_get_descriptor(this: *mut c_void) -> AlgoCDescriptor {
+    let myself = unsafe { as_control(this) };
+    AlgoCDescriptor::new(&myself.descriptor)
+}
+
+/// # Safety
+/// this must be a live SoundModule, see as_render.
+/// data must point to len readable bytes.
+pub unsafe fn soundmodule_send_midi(this: *mut c_void, data: *const u8, len: usize, timestamp: u64) {
+    let myself = unsafe { as_render(this) };
     let data = unsafe { slice::from_raw_parts(data, len) };
-    box_ref.send_midi(data,timestamp);
+    myself.send_midi(data,timestamp);
 }
 
-pub fn soundmodule_set_parameter(this: *mut c_void, address: u64, value: f32) {
-    let myself = as_soundmodule(this);
-    let _ = myself.param.set(value,address);
+/// Returns the value that was applied, the unchanged current value if the write was refused, or NaN if the
+/// module faulted
+///
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+pub unsafe fn soundmodule_set_parameter(this: *mut c_void, address: u64, value: f32) -> f32 {
+    let myself = unsafe { as_control(this) };
+    match myself.set_parameter(address, value) {
+        Ok(value) => value,
+        Err(SetParameterError::Faulted) => f32::NAN,
+        Err(_) => myself.get_parameter(address).unwrap_or(0.0),
+    }
+}
+
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+pub unsafe fn soundmodule_get_parameter(this: *mut c_void, address: u64) -> f32 {
+    let myself = unsafe { as_control(this) };
+    myself.get_parameter(address).unwrap_or(0.0)
+}
+
+/// Queues a change of the parameter at address for sample_time, moving there over ramp samples.
+/// Returns false if the address is unknown or read-only, or too many events are pending.
+///
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+pub unsafe fn soundmodule_schedule_parameter(this: *mut c_void, address: u64, value: f32, sample_time: u64, ramp: u32) -> bool {
+    unsafe { as_control(this) }.schedule_parameter(ParamEvent { address, value, sample_time, ramp }).is_ok()
+}
+
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+pub unsafe fn soundmodule_get_sample_time(this: *mut c_void) -> u64 {
+    unsafe { as_control(this) }.sample_time()
+}
+
+/// Copies up to max pending changes into changes, returns the number copied
+///
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+/// changes must be NULL or point to max writable AlgoCParamChange.
+pub unsafe fn soundmodule_poll_changes(this: *mut c_void, changes: *mut AlgoCParamChange, max: usize) -> usize {
+    if changes.is_null() {
+        return 0;
+    }
+    let myself = unsafe { as_control(this) };
+    let mut count = 0;
+    while count < max {
+        let Some(change) = myself.pop_change() else { break };
+        unsafe { *changes.add(count) = AlgoCParamChange::new(&change) };
+        count += 1;
+    }
+    count
 }
 
-pub fn soundmodule_get_parameter(this: *mut c_void, address: u64) -> f32 {
-    let myself = as_soundmodule(this);
-    myself.param.get(address).unwrap_or(0.0)
+/// A NULL callback goes back to queueing
+///
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+pub unsafe fn soundmodule_set_change_callback(this: *mut c_void, callback: Option<CChangeCallback>, context: *mut c_void) {
+    unsafe { as_control(this) }.set_change_callback(callback.map(|callback| notify::c_change_callback(callback, context)));
+}
+
+/// Batches are handed to C as boxed ParamBatch pointers, owned by the caller until commit or discard
+///
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+pub unsafe fn soundmodule_begin_batch(this: *mut c_void) -> *mut c_void {
+    Box::into_raw(Box::new(unsafe { as_control(this) }.begin_batch())) as *mut c_void
+}
+
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+/// batch must be NULL or a batch from soundmodule_begin_batch that hasn't been committed or discarded.
+pub unsafe fn soundmodule_batch_set(this: *mut c_void, batch: *mut c_void, address: u64, value: f32) -> bool {
+    if batch.is_null() {
+        return false;
+    }
+    let batch = unsafe { &mut *(batch as *mut ParamBatch) };
+    unsafe { as_control(this) }.stage_parameter(batch, address, value).is_ok()
+}
+
+/// Takes ownership of the batch, also when it can't be committed
+///
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+/// batch must be NULL or a batch from soundmodule_begin_batch that hasn't been committed or discarded.
+pub unsafe fn soundmodule_commit_batch(this: *mut c_void, batch: *mut c_void) -> bool {
+    if batch.is_null() {
+        return false;
+    }
+    let batch = unsafe { Box::from_raw(batch as *mut ParamBatch) };
+    unsafe { as_control(this) }.commit_batch(*batch).is_ok()
+}
+
+/// # Safety
+/// batch must be NULL or a batch from soundmodule_begin_batch that hasn't been committed or discarded.
+pub unsafe fn soundmodule_discard_batch(batch: *mut c_void) {
+    if !batch.is_null() {
+        drop(unsafe { Box::from_raw(batch as *mut ParamBatch) });
+    }
 }
 
-pub fn soundmodule_run(
+/// Returns the number of parameters reset, 0 for an unknown address
+///
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+pub unsafe fn soundmodule_reset_to_defaults(this: *mut c_void, address: u64) -> usize {
+    unsafe { as_control(this) }.reset_to_defaults(address).unwrap_or(0)
+}
+
+/// Reads count parameters at once, unknown addresses read as 0. Returns the number of known addresses.
+///
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+/// addresses and values must be NULL or point to count readable addresses and count writable values.
+pub unsafe fn soundmodule_get_parameters(this: *mut c_void, addresses: *const u64, values: *mut f32, count: usize) -> usize {
+    if count == 0 || addresses.is_null() || values.is_null() {
+        return 0;
+    }
+    let myself = unsafe { as_control(this) };
+    let addresses = unsafe { slice::from_raw_parts(addresses, count) };
+    let values = unsafe { slice::from_raw_parts_mut(values, count) };
+    myself.get_parameters(addresses, values)
+}
+
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+pub unsafe fn soundmodule_to_normalized(this: *mut c_void, address: u64, value: f32) -> f32 {
+    unsafe { as_control(this) }.to_normalized(address, value).unwrap_or(0.0)
+}
+
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+pub unsafe fn soundmodule_from_normalized(this: *mut c_void, address: u64, normalized: f32) -> f32 {
+    unsafe { as_control(this) }.from_normalized(address, normalized).unwrap_or(0.0)
+}
+
+/// Writes the display string for value into buf (see util::copy_to_c_buffer). Returns 0 for an unknown address.
+///
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+/// buf must be NULL or point to len writable bytes.
+pub unsafe fn soundmodule_format_parameter(this: *mut c_void, address: u64, value: f32, buf: *mut c_char, len: usize) -> usize {
+    let myself = unsafe { as_control(this) };
+    let text = myself.format_parameter(address, value);
+    unsafe { util::copy_to_c_buffer(text.as_deref().unwrap_or("").as_bytes(), buf, len) }
+}
+
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+/// text must be NULL or a null terminated string, value must be NULL or writable.
+pub unsafe fn soundmodule_parse_parameter(this: *mut c_void, address: u64, text: *const c_char, value: *mut f32) -> bool {
+    if text.is_null() || value.is_null() {
+        return false;
+    }
+    let myself = unsafe { as_control(this) };
+    let text = unsafe { CStr::from_ptr(text) }.to_string_lossy();
+    match myself.parse_parameter(address, &text) {
+        Some(v) => {
+            unsafe { *value = v };
+            true
+        },
+        None => false,
+    }
+}
+
+/// # Safety
+/// this must be a live SoundModule, see as_control.
+pub unsafe fn soundmodule_is_faulted(this: *mut c_void) -> bool {
+    unsafe { as_control(this) }.is_faulted()
+}
+
+/// # Safety
+/// this must be a live SoundModule, see as_render. The buffers must point to blksiz samples each.
+pub unsafe fn soundmodule_run(
     this: *mut c_void, 
     left_out: *mut f32,
     right_out: *mut f32,
@@ -84,58 +643,143 @@ pub fn soundmodule_run(
         let ro = unsafe {slice::from_raw_parts_mut(right_out, bz) };
         let li = unsafe {slice::from_raw_parts(left_in, bz) };
         let ri = unsafe {slice::from_raw_parts(right_in, bz) };
-        let myself = as_soundmodule(this);
+        let myself = unsafe { as_render(this) };
         
         let mut output = [lo,ro];
         let input = [li,ri];
 
-        myself.algo_state.process(&myself.parameter_zone, &mut output, &input);
+        myself.run(&mut output, &input);
 }
 
 // Public macros to re-export functions for the API
 #[macro_export]
 macro_rules! reexport_c_symbols_explicit {
+    () => {};
     (
-        $(fn $name:ident($($arg:ident : $arg_ty:ty),*) -> $ret:ty = $path:path;)*
+        unsafe fn $name:ident($($arg:ident : $arg_ty:ty),*) -> $ret:ty = $path:path; $($rest:tt)*
     ) => {
-        $(
-            #[unsafe(no_mangle)]
-            pub extern "C" fn $name($($arg : $arg_ty),*) -> $ret {
-                $path($($arg),*)
-            }
-        )*
+        /// # Safety
+        /// See the function this is exported from.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn $name($($arg : $arg_ty),*) -> $ret {
+            soundmodule::fault::ffi_guard(stringify!($name), || unsafe { $path($($arg),*) })
+        }
+        soundmodule::reexport_c_symbols_explicit! { $($rest)* }
+    };
+    (
+        fn $name:ident($($arg:ident : $arg_ty:ty),*) -> $ret:ty = $path:path; $($rest:tt)*
+    ) => {
+        #[unsafe(no_mangle)]
+        pub extern "C" fn $name($($arg : $arg_ty),*) -> $ret {
+            soundmodule::fault::ffi_guard(stringify!($name), || $path($($arg),*))
+        }
+        soundmodule::reexport_c_symbols_explicit! { $($rest)* }
     };
 }
 
 #[macro_export]
 macro_rules! reexport_c_symbols {
+    () => {};
     (
-        $(fn $name:ident($($arg:ident : $arg_ty:ty),*) -> $ret:ty;)*
+        unsafe fn $name:ident($($arg:ident : $arg_ty:ty),*) -> $ret:ty; $($rest:tt)*
     ) => {
-        $(
-            #[unsafe(no_mangle)]
-            pub extern "C" fn $name($($arg : $arg_ty),*) -> $ret {
-                soundmodule::$name($($arg),*)
-            }
-        )*
+        /// # Safety
+        /// See the soundmodule function of the same name.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn $name($($arg : $arg_ty),*) -> $ret {
+            soundmodule::fault::ffi_guard(stringify!($name), || unsafe { soundmodule::$name($($arg),*) })
+        }
+        soundmodule::reexport_c_symbols! { $($rest)* }
+    };
+    (
+        fn $name:ident($($arg:ident : $arg_ty:ty),*) -> $ret:ty; $($rest:tt)*
+    ) => {
+        #[unsafe(no_mangle)]
+        pub extern "C" fn $name($($arg : $arg_ty),*) -> $ret {
+            soundmodule::fault::ffi_guard(stringify!($name), || soundmodule::$name($($arg),*))
+        }
+        soundmodule::reexport_c_symbols! { $($rest)* }
     };
 }
 
 #[macro_export]
 macro_rules! soundmodule_api_import {
+    // Exports the API. Algorithms are registered by the crate itself through soundmodule::registry.
     () => {
+        soundmodule::soundmodule_api_import!(@api);
+        soundmodule::reexport_c_symbols_explicit! {
+            unsafe fn soundmodule_create(name: *const core::ffi::c_char) -> *mut core::ffi::c_void = soundmodule::registry::soundmodule_create;
+            fn soundmodule_list_algorithms() -> *const *const core::ffi::c_char = soundmodule::registry::soundmodule_list_algorithms;
+            unsafe fn soundmodule_describe_algorithm(name: *const core::ffi::c_char) -> soundmodule::descriptor::AlgoCDescriptor = soundmodule::registry::soundmodule_describe_algorithm;
+        }
+    };
+    // Exports the API and registers the listed algorithms the first time the host creates or lists them,
+    // e.g. soundmodule_api_import!("reverb" => || Box::new(Reverb::new()));
+    ($($algo_name:literal => $factory:expr),+ $(,)?) => {
+        soundmodule::soundmodule_api_import!(@api);
+
+        fn __soundmodule_register_algorithms() {
+            static REGISTERED: std::sync::Once = std::sync::Once::new();
+            REGISTERED.call_once(|| {
+                $(
+                    if let Err(e) = soundmodule::registry::register_algorithm($algo_name, $factory) {
+                        soundmodule::fault::set_last_error(&format!("registering '{}': {}", $algo_name, e));
+                    }
+                )+
+            });
+        }
+
+        unsafe fn __soundmodule_create(name: *const core::ffi::c_char) -> *mut core::ffi::c_void {
+            __soundmodule_register_algorithms();
+            unsafe { soundmodule::registry::soundmodule_create(name) }
+        }
+
+        fn __soundmodule_list_algorithms() -> *const *const core::ffi::c_char {
+            __soundmodule_register_algorithms();
+            soundmodule::registry::soundmodule_list_algorithms()
+        }
+
+        unsafe fn __soundmodule_describe_algorithm(name: *const core::ffi::c_char) -> soundmodule::descriptor::AlgoCDescriptor {
+            __soundmodule_register_algorithms();
+            unsafe { soundmodule::registry::soundmodule_describe_algorithm(name) }
+        }
+
+        soundmodule::reexport_c_symbols_explicit! {
+            unsafe fn soundmodule_create(name: *const core::ffi::c_char) -> *mut core::ffi::c_void = __soundmodule_create;
+            fn soundmodule_list_algorithms() -> *const *const core::ffi::c_char = __soundmodule_list_algorithms;
+            unsafe fn soundmodule_describe_algorithm(name: *const core::ffi::c_char) -> soundmodule::descriptor::AlgoCDescriptor = __soundmodule_describe_algorithm;
+        }
+    };
+    (@api) => {
         use soundmodule::algoparam;
         use soundmodule::algoparam::{AlgoCParam,AlgoCParamSet};
 
 
         soundmodule::reexport_c_symbols! {
-            fn soundmodule_init(this: *mut core::ffi::c_void, fs: i32) -> ();
-            fn soundmodule_release(this: *mut core::ffi::c_void) -> ();
-            fn soundmodule_get_params(this: *mut core::ffi::c_void) -> *const core::ffi::c_void;
-            fn soundmodule_send_midi(this: *mut core::ffi::c_void, data: *const u8, len: usize, timestamp: u64) -> ();
-            fn soundmodule_set_parameter(this: *mut core::ffi::c_void, address: u64, value: f32) -> ();
-            fn soundmodule_get_parameter(this: *mut core::ffi::c_void, address: u64) -> f32;
-            fn soundmodule_run(
+            unsafe fn soundmodule_init(this: *mut core::ffi::c_void, fs: i32) -> ();
+            unsafe fn soundmodule_release(this: *mut core::ffi::c_void) -> ();
+            unsafe fn soundmodule_get_params(this: *mut core::ffi::c_void) -> *const core::ffi::c_void;
+            unsafe fn soundmodule_get_tree_generation(this: *mut core::ffi::c_void) -> u64;
+            unsafe fn soundmodule_get_descriptor(this: *mut core::ffi::c_void) -> soundmodule::descriptor::AlgoCDescriptor;
+            unsafe fn soundmodule_send_midi(this: *mut core::ffi::c_void, data: *const u8, len: usize, timestamp: u64) -> ();
+            unsafe fn soundmodule_set_parameter(this: *mut core::ffi::c_void, address: u64, value: f32) -> f32;
+            unsafe fn soundmodule_get_parameter(this: *mut core::ffi::c_void, address: u64) -> f32;
+            unsafe fn soundmodule_schedule_parameter(this: *mut core::ffi::c_void, address: u64, value: f32, sample_time: u64, ramp: u32) -> bool;
+            unsafe fn soundmodule_get_sample_time(this: *mut core::ffi::c_void) -> u64;
+            unsafe fn soundmodule_poll_changes(this: *mut core::ffi::c_void, changes: *mut soundmodule::notify::AlgoCParamChange, max: usize) -> usize;
+            unsafe fn soundmodule_set_change_callback(this: *mut core::ffi::c_void, callback: Option<soundmodule::notify::CChangeCallback>, context: *mut core::ffi::c_void) -> ();
+            unsafe fn soundmodule_begin_batch(this: *mut core::ffi::c_void) -> *mut core::ffi::c_void;
+            unsafe fn soundmodule_batch_set(this: *mut core::ffi::c_void, batch: *mut core::ffi::c_void, address: u64, value: f32) -> bool;
+            unsafe fn soundmodule_commit_batch(this: *mut core::ffi::c_void, batch: *mut core::ffi::c_void) -> bool;
+            unsafe fn soundmodule_discard_batch(batch: *mut core::ffi::c_void) -> ();
+            unsafe fn soundmodule_reset_to_defaults(this: *mut core::ffi::c_void, address: u64) -> usize;
+            unsafe fn soundmodule_get_parameters(this: *mut core::ffi::c_void, addresses: *const u64, values: *mut f32, count: usize) -> usize;
+            unsafe fn soundmodule_to_normalized(this: *mut core::ffi::c_void, address: u64, value: f32) -> f32;
+            unsafe fn soundmodule_from_normalized(this: *mut core::ffi::c_void, address: u64, normalized: f32) -> f32;
+            unsafe fn soundmodule_format_parameter(this: *mut core::ffi::c_void, address: u64, value: f32, buf: *mut core::ffi::c_char, len: usize) -> usize;
+            unsafe fn soundmodule_parse_parameter(this: *mut core::ffi::c_void, address: u64, text: *const core::ffi::c_char, value: *mut f32) -> bool;
+            unsafe fn soundmodule_is_faulted(this: *mut core::ffi::c_void) -> bool;
+            unsafe fn soundmodule_run(
                 this: *mut core::ffi::c_void, 
                 left_out: *mut f32,
                 right_out: *mut f32,
@@ -144,10 +788,12 @@ macro_rules! soundmodule_api_import {
                 blksiz: u32) -> ();
         }
         soundmodule::reexport_c_symbols_explicit! {
-            fn algoparam_get_first_set(tree: *const core::ffi::c_void, basekey: *mut u64) -> AlgoCParamSet = algoparam::algoparam_get_first_set;
-            fn algoparam_get_next_set(tree: *const core::ffi::c_void, basekey: *mut u64) -> AlgoCParamSet = algoparam::algoparam_get_next_set; 
-            fn algoparam_get_first_param(tree: *const core::ffi::c_void, basekey: *mut u64) -> AlgoCParam = algoparam::algoparam_get_first_param;
-            fn algoparam_get_next_param(tree: *const core::ffi::c_void, basekey: *mut u64) -> AlgoCParam = algoparam::algoparam_get_next_param;
+            unsafe fn algoparam_get_first_set(tree: *const core::ffi::c_void, basekey: *mut u64) -> AlgoCParamSet = algoparam::algoparam_get_first_set;
+            unsafe fn algoparam_get_next_set(tree: *const core::ffi::c_void, basekey: *mut u64) -> AlgoCParamSet = algoparam::algoparam_get_next_set; 
+            unsafe fn algoparam_get_first_param(tree: *const core::ffi::c_void, basekey: *mut u64) -> AlgoCParam = algoparam::algoparam_get_first_param;
+            unsafe fn algoparam_get_next_param(tree: *const core::ffi::c_void, basekey: *mut u64) -> AlgoCParam = algoparam::algoparam_get_next_param;
+            unsafe fn soundmodule_last_error(buf: *mut core::ffi::c_char, len: usize) -> usize = soundmodule::fault::soundmodule_last_error;
+            fn soundmodule_clear_error() -> () = soundmodule::fault::soundmodule_clear_error;
         }
     };
 }
\ No newline at end of file
@@ -0,0 +1,239 @@
+use std::{ffi::c_void, sync::{Arc, OnceLock, RwLock}};
+
+use crate::{algoparam::AlgoParamSet, tree::{ParamTree, TreeError}, util::BoundedQueue};
+
+// Parameter changes made by the algorithm itself (MIDI learn, randomize, dependent values) on their way to
+// the host. Dependents changed by AlgoParamSet::set_reporting are sent the same way. The algorithm reports them through a ParamNotifier from any thread, including the audio thread.
+// A registered callback gets them right away on the reporting thread, possibly from several threads at
+// once. Otherwise (or when the callback is being replaced at that moment) they wait in a lock-free queue
+// for the host to poll.
+
+// Changes that can wait for the host. Further changes are dropped.
+pub const CHANGE_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamChange {
+    Value { address: u64, value: f32 },
+    // The algorithm starts or stops changing the parameter continuously, like a user dragging a knob
+    BeginGesture { address: u64 },
+    EndGesture { address: u64 },
+    // The set at address has been rebuilt, see tree::ParamTree. Addresses inside it may now refer to other
+    // parameters, hosts rebuild their parameter model from the new tree.
+    TreeChanged { address: u64 },
+}
+
+pub type ChangeCallback = Box<dyn Fn(ParamChange) + Send + Sync>;
+
+pub struct ChangeChannel {
+    queue: BoundedQueue<ParamChange>,
+    callback: RwLock<Option<ChangeCallback>>,
+    tree: OnceLock<Arc<ParamTree>>,
+}
+
+impl ChangeChannel {
+    pub fn new() -> Arc<ChangeChannel> {
+        Arc::new(ChangeChannel { queue: BoundedQueue::new(CHANGE_CAPACITY), callback: RwLock::new(None), tree: OnceLock::new() })
+    }
+
+    // Called once the parameter tree exists, so notifiers can resolve keypaths
+    pub fn attach_tree(&self, tree: Arc<ParamTree>) {
+        let _ = self.tree.set(tree);
+    }
+
+    pub fn set_callback(&self, callback: Option<ChangeCallback>) {
+        *self.callback.write().unwrap_or_else(|e| e.into_inner()) = callback;
+    }
+
+    pub fn pop(&self) -> Option<ParamChange> {
+        self.queue.pop()
+    }
+
+    pub fn send(&self, change: ParamChange) {
+        // Never wait for the lock, the sender may be the audio thread. Senders share the read lock, so only
+        // set_callback makes this fail.
+        if let Ok(callback) = self.callback.try_read()
+            && let Some(callback) = callback.as_ref() {
+            callback(change);
+            return;
+        }
+        let _ = self.queue.push(change);
+    }
+}
+
+// Handed to the algorithm through Algorithm::set_notifier
+#[derive(Clone)]
+pub struct ParamNotifier {
+    channel: Arc<ChangeChannel>,
+    scope: String,      // keypath prefix ending in '.', empty for the module's own algorithm
+}
+
+impl ParamNotifier {
+    pub fn new(channel: Arc<ChangeChannel>) -> ParamNotifier {
+        ParamNotifier { channel, scope: String::new() }
+    }
+
+    // Notifier for an algorithm whose set is mounted as identifier in this one's (see submodule::Submodule).
+    // Its keypaths are relative to that set.
+    pub fn scoped(&self, identifier: &str) -> ParamNotifier {
+        ParamNotifier { channel: self.channel.clone(), scope: format!("{}{}.", self.scope, identifier) }
+    }
+
+    fn keypath(&self, keypath: &str) -> String {
+        format!("{}{}", self.scope, keypath)
+    }
+
+    // Reports that the algorithm has set the parameter at address to value
+    pub fn changed(&self, address: u64, value: f32) {
+        self.channel.send(ParamChange::Value { address, value });
+    }
+
+    pub fn begin_gesture(&self, address: u64) {
+        self.channel.send(ParamChange::BeginGesture { address });
+    }
+
+    pub fn end_gesture(&self, address: u64) {
+        self.channel.send(ParamChange::EndGesture { address });
+    }
+
+    // Address of a parameter in the module's tree, e.g. "filter.cutoff". None until the tree has been built.
+    // Doesn't lock or allocate, so it may be called from process.
+    pub fn address_of(&self, keypath: &str) -> Option<u64> {
+        let elems = self.scope.split_terminator('.').chain(keypath.split('.'));
+        self.channel.tree.get()?.read(|tree, _| tree.address_of_path(elems))
+    }
+
+    // Replaces the set at keypath with set (see tree::ParamTree::replace_subtree) and tells the host.
+    // Returns the new tree generation. Builds and frees trees, so not for the audio thread.
+    pub fn replace_subtree(&self, keypath: &str, set: AlgoParamSet) -> Result<u64, TreeError> {
+        let tree = self.channel.tree.get().ok_or(TreeError::NotAttached)?;
+        let (address, generation) = tree.replace_subtree(&self.keypath(keypath), set)?;
+        self.channel.send(ParamChange::TreeChanged { address });
+        Ok(generation)
+    }
+}
+
+pub const ALGO_CHANGE_VALUE: i32 = 0;
+pub const ALGO_CHANGE_BEGIN_GESTURE: i32 = 1;
+pub const ALGO_CHANGE_END_GESTURE: i32 = 2;
+pub const ALGO_CHANGE_TREE_CHANGED: i32 = 3;
+
+/// C representation of a ParamChange
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct AlgoCParamChange {
+    pub kind: i32,
+    pub address: u64,
+    pub value: f32,     // only for ALGO_CHANGE_VALUE
+}
+
+impl AlgoCParamChange {
+    pub fn new(change: &ParamChange) -> AlgoCParamChange {
+        match *change {
+            ParamChange::Value { address, value } => AlgoCParamChange { kind: ALGO_CHANGE_VALUE, address, value },
+            ParamChange::BeginGesture { address } => AlgoCParamChange { kind: ALGO_CHANGE_BEGIN_GESTURE, address, value: 0.0 },
+            ParamChange::EndGesture { address } => AlgoCParamChange { kind: ALGO_CHANGE_END_GESTURE, address, value: 0.0 },
+            ParamChange::TreeChanged { address } => AlgoCParamChange { kind: ALGO_CHANGE_TREE_CHANGED, address, value: 0.0 },
+        }
+    }
+}
+
+pub type CChangeCallback = extern "C" fn(context: *mut c_void, change: AlgoCParamChange);
+
+// The context pointer belongs to the host, which promises it may be used from any thread
+struct CCallbackContext(*mut c_void);
+unsafe impl Send for CCallbackContext {}
+unsafe impl Sync for CCallbackContext {}
+
+impl CCallbackContext {
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+pub fn c_change_callback(callback: CChangeCallback, context: *mut c_void) -> ChangeCallback {
+    let context = CCallbackContext(context);
+    Box::new(move |change| callback(context.get(), AlgoCParamChange::new(&change)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{algoparam::{AlgoParam, AlgoParamNode}, util::AtomicF32, Algorithm, SoundModule};
+    use std::{sync::{atomic::{AtomicUsize, Ordering}, Mutex}, thread, time::{Duration, Instant}};
+
+    // Sets its level from MIDI CC 7, like a MIDI learned knob
+    struct Learn {
+        level: Arc<AtomicF32>,
+        notifier: Option<ParamNotifier>,
+    }
+
+    impl Algorithm for Learn {
+        type Params = ();
+
+        fn init(&mut self, _fs: i32) {}
+
+        fn set_notifier(&mut self, notifier: ParamNotifier) {
+            self.notifier = Some(notifier);
+        }
+
+        fn get_parameters(&self, basename: &str, displayname: &str) -> (AlgoParamSet, ()) {
+            let mut root = AlgoParamSet::new(basename, displayname);
+            root.add(AlgoParamNode::Param(AlgoParam::builder("level").bind(&self.level).build().unwrap())).unwrap();
+            (root, ())
+        }
+
+        fn process(&self, _params: &(), _outputs: &mut [&mut [f32]], _inputs: &[&[f32]]) {}
+
+        fn send_midi(&self, data: &[u8], _timestamp: u64) {
+            if let (Some(notifier), [0xb0, 7, value]) = (&self.notifier, data) {
+                let address = notifier.address_of("level").unwrap();
+                let value = *value as f32 / 127.0;
+                self.level.store(value, Ordering::Relaxed);
+                notifier.begin_gesture(address);
+                notifier.changed(address, value);
+                notifier.end_gesture(address);
+            }
+        }
+    }
+
+    #[test]
+    fn test_change_notifications() {
+        let module = SoundModule::new(Learn { level: Arc::new(AtomicF32::new(0.0)), notifier: None });
+        let address = module.control.param().address_of("level").unwrap();
+        module.render.send_midi(&[0xb0, 7, 127], 0);
+        assert_eq!(module.control.pop_change(), Some(ParamChange::BeginGesture { address }));
+        assert_eq!(module.control.pop_change(), Some(ParamChange::Value { address, value: 1.0 }));
+        assert_eq!(module.control.pop_change(), Some(ParamChange::EndGesture { address }));
+        assert_eq!(module.control.pop_change(), None);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        module.control.set_change_callback(Some(Box::new(move |change| sink.lock().unwrap().push(change))));
+        module.render.send_midi(&[0xb0, 7, 0], 0);
+        assert_eq!(seen.lock().unwrap()[1], ParamChange::Value { address, value: 0.0 });
+        assert_eq!(module.control.pop_change(), None);
+    }
+
+    #[test]
+    fn test_concurrent_senders_reach_callback() {
+        // Each call waits until both senders are inside the callback at the same time
+        let (inside, delivered) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let channel = ChangeChannel::new();
+        let (counter, count) = (inside.clone(), delivered.clone());
+        channel.set_callback(Some(Box::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let start = Instant::now();
+            while counter.load(Ordering::SeqCst) < 2 && start.elapsed() < Duration::from_secs(5) {
+                std::hint::spin_loop();
+            }
+            count.fetch_add(1, Ordering::SeqCst);
+        })));
+        let senders: Vec<_> = (0..2).map(|address| {
+            let channel = channel.clone();
+            thread::spawn(move || channel.send(ParamChange::BeginGesture { address }))
+        }).collect();
+        senders.into_iter().for_each(|sender| sender.join().unwrap());
+        assert_eq!(delivered.load(Ordering::SeqCst), 2);
+        assert_eq!(channel.pop(), None);
+    }
+}
@@ -0,0 +1,220 @@
+use std::{ffi::{c_char, c_void, CStr, CString}, ptr::{null, null_mut}, sync::Mutex};
+
+use crate::{descriptor::{AlgoCDescriptor, AlgoDescriptor}, fault, DynAlgorithm, SoundModule};
+
+// Process wide registry of named algorithm constructors. Host crates register their algorithms
+// (directly or through soundmodule_api_import!) and the host creates SoundModules by name.
+
+pub type AlgorithmFactory = fn() -> Box<dyn DynAlgorithm>;
+
+#[derive(Debug)]
+pub enum RegistryError {
+    InvalidName,
+    DuplicateName,
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RegistryError::InvalidName => write!(f, "Algorithm name is empty or contains a null byte"),
+            RegistryError::DuplicateName => write!(f, "An algorithm with this name is already registered"),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+struct Entry {
+    name: CString,
+    factory: AlgorithmFactory,
+    // Filled on first request by instantiating the algorithm once. Leaked so C can hold on to the strings.
+    descriptor: Option<&'static AlgoDescriptor>,
+}
+
+struct Registry {
+    entries: Vec<Entry>,
+    // Null terminated raw array for FFI, rebuilt on every registration. Earlier arrays are leaked so
+    // pointers handed out before stay valid. The names are owned by the entries, which are never removed.
+    names: &'static [*const c_char],
+}
+
+// The raw pointers only refer to the CStrings owned by the registry itself
+unsafe impl Send for Registry {}
+
+impl Registry {
+    fn find(&self, name: &[u8]) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.name.as_bytes() == name)
+    }
+
+    fn find_mut(&mut self, name: &[u8]) -> Option<&mut Entry> {
+        self.entries.iter_mut().find(|e| e.name.as_bytes() == name)
+    }
+
+    fn rebuild_names(&mut self) {
+        let names: Vec<*const c_char> = self.entries.iter().map(|e| e.name.as_ptr()).chain([null()]).collect();
+        self.names = Box::leak(names.into_boxed_slice());
+    }
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry { entries: Vec::new(), names: &[null()] });
+
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn register_algorithm(name: &str, factory: AlgorithmFactory) -> Result<(), RegistryError> {
+    if name.is_empty() {
+        return Err(RegistryError::InvalidName);
+    }
+    let name = CString::new(name).map_err(|_| RegistryError::InvalidName)?;
+    let mut reg = registry();
+    if reg.find(name.as_bytes()).is_some() {
+        return Err(RegistryError::DuplicateName);
+    }
+    reg.entries.push(Entry { name, factory, descriptor: None });
+    reg.rebuild_names();
+    Ok(())
+}
+
+pub fn algorithm_names() -> Vec<String> {
+    registry().entries.iter().map(|e| e.name.to_string_lossy().into_owned()).collect()
+}
+
+pub fn create_algorithm(name: &str) -> Option<Box<dyn DynAlgorithm>> {
+    // Don't hold the lock while running user code
+    let factory = registry().find(name.as_bytes())?.factory;
+    Some(factory())
+}
+
+fn describe(name: &str) -> Option<&'static AlgoDescriptor> {
+    let factory = {
+        let reg = registry();
+        let entry = reg.find(name.as_bytes())?;
+        if let Some(descriptor) = entry.descriptor {
+            return Some(descriptor);
+        }
+        entry.factory
+    };
+    // Don't hold the lock while running user code
+    let descriptor = factory().descriptor();
+    let mut reg = registry();
+    let entry = reg.find_mut(name.as_bytes())?;
+    Some(*entry.descriptor.get_or_insert_with(|| Box::leak(Box::new(descriptor))))
+}
+
+pub fn algorithm_descriptor(name: &str) -> Option<AlgoDescriptor> {
+    describe(name).cloned()
+}
+
+// API functions without name mangling
+
+/// Creates a SoundModule running the named algorithm. Returns NULL (and sets the last error) if no
+/// algorithm with that name is registered. The module must be freed with soundmodule_release.
+///
+/// # Safety
+/// name must be NULL or a null terminated string.
+pub unsafe fn soundmodule_create(name: *const c_char) -> *mut c_void {
+    if name.is_null() {
+        fault::set_last_error("soundmodule_create: name is NULL");
+        return null_mut();
+    }
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+    match create_algorithm(&name) {
+        Some(algo) => Box::into_raw(Box::new(SoundModule::from_dyn(algo))) as *mut c_void,
+        None => {
+            fault::set_last_error(&format!("soundmodule_create: unknown algorithm '{}'", name));
+            null_mut()
+        }
+    }
+}
+
+/// Returns a null terminated array of the registered algorithm names. The array stays valid for the
+/// lifetime of the process, algorithms registered later are only listed by later calls.
+pub fn soundmodule_list_algorithms() -> *const *const c_char {
+    registry().names.as_ptr()
+}
+
+/// Describes a registered algorithm without creating a module. The returned strings stay valid for the
+/// lifetime of the process. Returns a descriptor with NULL name if the algorithm is unknown.
+///
+/// # Safety
+/// name must be NULL or a null terminated string.
+pub unsafe fn soundmodule_describe_algorithm(name: *const c_char) -> AlgoCDescriptor {
+    if name.is_null() {
+        fault::set_last_error("soundmodule_describe_algorithm: name is NULL");
+        return AlgoCDescriptor::null();
+    }
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+    match describe(&name) {
+        Some(descriptor) => AlgoCDescriptor::new(descriptor),
+        None => {
+            fault::set_last_error(&format!("soundmodule_describe_algorithm: unknown algorithm '{}'", name));
+            AlgoCDescriptor::null()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{algoparam::AlgoParamSet, descriptor::*, soundmodule_release, Algorithm};
+
+    struct Silence;
+
+    impl Algorithm for Silence {
+        type Params = ();
+
+        fn init(&mut self, _fs: i32) {}
+        fn get_parameters(&self, basename: &str, displayname: &str) -> (AlgoParamSet, ()) {
+            (AlgoParamSet::new(basename, displayname), ())
+        }
+        fn process(&self, _params: &(), outputs: &mut [&mut [f32]], _inputs: &[&[f32]]) {
+            for channel in outputs.iter_mut() {
+                channel.fill(0.0);
+            }
+        }
+        fn send_midi(&self, _data: &[u8], _timestamp: u64) {}
+        fn descriptor(&self) -> AlgoDescriptor {
+            AlgoDescriptor::new("Silence", "Test", AlgoVersion::new(1, 0, 0), AlgoCategory::EFFECT,
+                AlgoUniqueId::fourcc(b"Slnc"), AlgoFeatures::AUDIO_OUTPUT)
+        }
+    }
+
+    #[test]
+    fn test_register_and_create() {
+        register_algorithm("registry_test_silence", || Box::new(Silence)).unwrap();
+        assert!(matches!(register_algorithm("registry_test_silence", || Box::new(Silence)), Err(RegistryError::DuplicateName)));
+        assert!(matches!(register_algorithm("", || Box::new(Silence)), Err(RegistryError::InvalidName)));
+
+        let names = |mut ptr: *const *const c_char| {
+            let mut names = Vec::new();
+            unsafe {
+                while !(*ptr).is_null() {
+                    names.push(CStr::from_ptr(*ptr).to_str().unwrap().to_string());
+                    ptr = ptr.add(1);
+                }
+            }
+            names
+        };
+        let listed = soundmodule_list_algorithms();
+        assert!(names(listed).iter().any(|n| n == "registry_test_silence"));
+
+        // Arrays handed out earlier survive later registrations
+        register_algorithm("registry_test_later", || Box::new(Silence)).unwrap();
+        assert!(!names(listed).iter().any(|n| n == "registry_test_later"));
+        assert!(names(soundmodule_list_algorithms()).iter().any(|n| n == "registry_test_later"));
+
+        let name = CString::new("registry_test_silence").unwrap();
+        let module = unsafe { soundmodule_create(name.as_ptr()) };
+        assert!(!module.is_null());
+        unsafe { soundmodule_release(module) };
+
+        let descriptor = unsafe { soundmodule_describe_algorithm(name.as_ptr()) };
+        assert_eq!(unsafe { CStr::from_ptr(descriptor.name) }.to_str().unwrap(), "Silence");
+        assert_eq!(descriptor.id, *b"Slnc\0\0\0\0\0\0\0\0\0\0\0\0");
+
+        let name = CString::new("registry_test_missing").unwrap();
+        assert!(unsafe { soundmodule_create(name.as_ptr()) }.is_null());
+        assert!(unsafe { soundmodule_describe_algorithm(name.as_ptr()) }.name.is_null());
+    }
+}
@@ -0,0 +1,499 @@
+use std::{f32::consts::FRAC_PI_2, fmt, mem, sync::{atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering}, Arc, Condvar, Mutex, OnceLock}, thread::{self, JoinHandle, Thread}, time::{Duration, Instant}};
+
+use crate::{algoparam::{AlgoParam, AlgoParamNode, AlgoParamSet, AlgoParamUnit}, fault, notify::ParamNotifier, registry, sub_block, util::BoundedQueue, Algorithm, BoundAlgorithm, MAX_SPLIT_CHANNELS};
+
+// A slot running one of several registered algorithms, chosen by its INDEXED "model" parameter (e.g. the
+// filter model of a voice). The parameters of the running algorithm are in the "algorithm" set of the slot.
+//
+// Changing the model wakes a loader thread, which creates and initializes the new algorithm and replaces
+// the "algorithm" set (ParamNotifier::replace_subtree) while the old algorithm keeps running. The render
+// side picks the new algorithm up at the start of a block and crossfades to it over FADE_TIME. Replaced
+// algorithms are freed on the loader thread.
+//
+// The slot creates algorithms with the notifier it is given, so a parent must forward set_notifier to it
+// (see submodule::Submodule). Without a notifier the slot stays empty and outputs silence.
+//
+// The outcome of the last load is available through AlgorithmSlot::status, e.g. to show an error when a
+// model's algorithm isn't registered.
+
+// Crossfade length in seconds
+pub const FADE_TIME: f32 = 0.01;
+// Samples rendered at a time while crossfading
+const FADE_CHUNK: usize = 256;
+// Blocks with more channels than this switch without crossfade
+const MAX_FADE_CHANNELS: usize = MAX_SPLIT_CHANNELS;
+// Algorithms on their way between the loader and the render side
+const SWAP_CAPACITY: usize = 4;
+
+pub const MODEL_KEY: &str = "model";
+pub const ALGORITHM_KEY: &str = "algorithm";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlotError {
+    // The model index is past the models given to AlgorithmSlot::new
+    UnknownModel,
+    // No algorithm is registered under the model's name
+    UnknownAlgorithm,
+    // The algorithm panicked while being created or initialized
+    Panicked,
+    // The slot has no notifier or its set isn't part of a module's tree
+    NotAttached,
+    // Too many loaded algorithms are waiting for the render side
+    Busy,
+}
+
+impl fmt::Display for SlotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SlotError::UnknownModel => write!(f, "No model with that index"),
+            SlotError::UnknownAlgorithm => write!(f, "The model's algorithm is not registered"),
+            SlotError::Panicked => write!(f, "The model's algorithm panicked while loading"),
+            SlotError::NotAttached => write!(f, "The slot is not part of a module"),
+            SlotError::Busy => write!(f, "The render side is not taking loaded algorithms"),
+        }
+    }
+}
+
+impl std::error::Error for SlotError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadState {
+    // Before the slot's parameters are built
+    Empty,
+    // The model's set is in the tree and its algorithm is handed to the render side, which switches to it
+    // at the start of the next block
+    Loaded(usize),
+    // The model couldn't be loaded, the slot keeps running the one before
+    Failed(usize, SlotError),
+}
+
+impl LoadState {
+    fn model(&self) -> Option<usize> {
+        match self {
+            LoadState::Empty => None,
+            LoadState::Loaded(model) | LoadState::Failed(model, _) => Some(*model),
+        }
+    }
+}
+
+// The part of the slot the model parameter's setter uses. Kept apart from Shared, so the parameter tree
+// doesn't hold on to the notifier (and through it to the tree itself).
+struct Selector {
+    requested: AtomicU32,
+    loader: OnceLock<Thread>,
+    // Written after each load, never by the setter, which may run on the audio thread
+    state: Mutex<LoadState>,
+    loaded: Condvar,
+}
+
+impl Selector {
+    fn wake_loader(&self) {
+        if let Some(loader) = self.loader.get() {
+            loader.unpark();
+        }
+    }
+
+    fn report(&self, state: LoadState) {
+        *self.state.lock().unwrap_or_else(|e| e.into_inner()) = state;
+        self.loaded.notify_all();
+    }
+}
+
+// Shareable view of a slot's loads, see AlgorithmSlot::status
+#[derive(Clone)]
+pub struct SlotStatus {
+    selector: Arc<Selector>,
+}
+
+impl SlotStatus {
+    // The outcome of the last load, which may be of a model other than the one selected while the loader is busy
+    pub fn state(&self) -> LoadState {
+        *self.selector.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Waits until the selected model has been loaded or has failed to load. Returns None on timeout.
+    pub fn wait(&self, timeout: Duration) -> Option<LoadState> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.selector.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            let requested = self.selector.requested.load(Ordering::Acquire) as usize;
+            if state.model() == Some(requested) {
+                return Some(*state);
+            }
+            let remaining = deadline.checked_duration_since(Instant::now())?;
+            state = self.selector.loaded.wait_timeout(state, remaining).unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+}
+
+struct Shared {
+    models: Vec<String>,
+    selector: Arc<Selector>,
+    fs: AtomicI32,
+    notifier: OnceLock<ParamNotifier>,
+    loaded: BoundedQueue<Box<dyn BoundAlgorithm>>,      // to the render side
+    retired: BoundedQueue<Box<dyn BoundAlgorithm>>,     // back to the loader
+    shutdown: AtomicBool,
+}
+
+impl Shared {
+    // Creates and initializes the algorithm of a model. Returns its parameter set, reset to the defaults.
+    fn instantiate(&self, model: usize) -> Result<(AlgoParamSet, Box<dyn BoundAlgorithm>), SlotError> {
+        let name = self.models.get(model).ok_or(SlotError::UnknownModel)?;
+        let notifier = self.notifier.get().ok_or(SlotError::NotAttached)?.scoped(ALGORITHM_KEY);
+        fault::catch("slot", || {
+            let (set, mut bound) = registry::create_algorithm(name).ok_or(SlotError::UnknownAlgorithm)?.bind(ALGORITHM_KEY, name, notifier);
+            set.reset_to_defaults();
+            let fs = self.fs.load(Ordering::Acquire);
+            if fs > 0 {
+                bound.init(fs);
+            }
+            Ok((set, bound))
+        }).unwrap_or(Err(SlotError::Panicked))
+    }
+
+    // Loader side: hands the model's algorithm to the render side and swaps its set into the tree. The
+    // algorithm is queued first, so the tree never shows a model the render side won't switch to.
+    fn load(&self, model: usize) -> Result<(), SlotError> {
+        let (set, algo) = self.instantiate(model)?;
+        let notifier = self.notifier.get().ok_or(SlotError::NotAttached)?;
+        if let Err(algo) = self.loaded.push(algo) {
+            // The render side isn't running blocks. It would skip the oldest waiting algorithm anyway, so that
+            // one makes room and is freed here.
+            drop(self.loaded.pop());
+            self.loaded.push(algo).map_err(|_| SlotError::Busy)?;
+        }
+        notifier.replace_subtree(ALGORITHM_KEY, set).map_err(|_| SlotError::NotAttached)?;
+        Ok(())
+    }
+
+    // Hands an algorithm the render side is done with to the loader
+    fn retire(&self, algo: Box<dyn BoundAlgorithm>) {
+        // Only freed here if the loader falls behind
+        let _ = self.retired.push(algo);
+        self.selector.wake_loader();
+    }
+}
+
+fn run_loader(shared: Arc<Shared>, mut current: u32) {
+    while !shared.shutdown.load(Ordering::Acquire) {
+        while shared.retired.pop().is_some() {}
+        let model = shared.selector.requested.load(Ordering::Acquire);
+        if model == current {
+            thread::park();
+            continue;
+        }
+        // Not retried if the model can't be created
+        current = model;
+        let model = model as usize;
+        shared.selector.report(match shared.load(model) {
+            Ok(()) => LoadState::Loaded(model),
+            Err(e) => LoadState::Failed(model, e),
+        });
+    }
+}
+
+// Render side state, only locked by the audio thread (and init)
+struct SlotRender {
+    active: Option<Box<dyn BoundAlgorithm>>,
+    fading: Option<Box<dyn BoundAlgorithm>>,    // crossfaded out, the one active before
+    fade_pos: usize,
+    fade_len: usize,
+    scratch: Vec<[f32; FADE_CHUNK]>,
+}
+
+impl SlotRender {
+    fn render(&mut self, shared: &Shared, outputs: &mut [&mut [f32]], inputs: &[&[f32]]) {
+        // Start the next crossfade once the current one is done, skipping models that were replaced meanwhile
+        if self.fading.is_none() && let Some(mut next) = shared.loaded.pop() {
+            while let Some(newer) = shared.loaded.pop() {
+                shared.retire(mem::replace(&mut next, newer));
+            }
+            self.fading = self.active.replace(next);
+            self.fade_pos = 0;
+        }
+        let Some(active) = self.active.as_ref() else {
+            for channel in outputs.iter_mut() {
+                channel.fill(0.0);
+            }
+            return;
+        };
+        let (n_out, n_in) = (outputs.len(), inputs.len());
+        if (n_out > MAX_FADE_CHANNELS || n_in > MAX_FADE_CHANNELS) && let Some(fading) = self.fading.take() {
+            shared.retire(fading);
+        }
+        let Some(fading) = self.fading.as_ref() else {
+            active.process(outputs, inputs);
+            return;
+        };
+
+        let len = outputs.iter().map(|o| o.len()).chain(inputs.iter().map(|i| i.len())).min().unwrap_or(0);
+        let mut start = 0;
+        while start < len && self.fade_pos < self.fade_len {
+            let end = (start + FADE_CHUNK.min(self.fade_len - self.fade_pos)).min(len);
+            let (mut sub_out, sub_in) = sub_block(outputs, inputs, start, end);
+            let mut old: [&mut [f32]; MAX_FADE_CHANNELS] = Default::default();
+            for (sub, channel) in old.iter_mut().zip(self.scratch.iter_mut()) {
+                *sub = &mut channel[..end - start];
+            }
+            fading.process(&mut old[..n_out], &sub_in[..n_in]);
+            active.process(&mut sub_out[..n_out], &sub_in[..n_in]);
+            // Equal power, the two algorithms' outputs are unrelated
+            for (new, old) in sub_out.iter_mut().zip(old.iter()).take(n_out) {
+                for (i, (n, o)) in new.iter_mut().zip(old.iter()).enumerate() {
+                    let x = (self.fade_pos + i) as f32 / self.fade_len as f32 * FRAC_PI_2;
+                    *n = *n * x.sin() + *o * x.cos();
+                }
+            }
+            self.fade_pos += end - start;
+            start = end;
+        }
+        if self.fade_pos >= self.fade_len && let Some(fading) = self.fading.take() {
+            shared.retire(fading);
+        }
+        if start < len {
+            let (mut sub_out, sub_in) = sub_block(outputs, inputs, start, len);
+            active.process(&mut sub_out[..n_out], &sub_in[..n_in]);
+        }
+    }
+}
+
+pub struct AlgorithmSlot {
+    shared: Arc<Shared>,
+    render: Mutex<SlotRender>,
+    loader: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AlgorithmSlot {
+    // models are names of registered algorithms (see registry::register_algorithm). The first one starts out in the slot.
+    pub fn new(models: &[&str]) -> AlgorithmSlot {
+        let selector = Arc::new(Selector { requested: AtomicU32::new(0), loader: OnceLock::new(), state: Mutex::new(LoadState::Empty), loaded: Condvar::new() });
+        AlgorithmSlot {
+            shared: Arc::new(Shared {
+                models: models.iter().map(|m| m.to_string()).collect(),
+                selector,
+                fs: AtomicI32::new(0),
+                notifier: OnceLock::new(),
+                loaded: BoundedQueue::new(SWAP_CAPACITY),
+                retired: BoundedQueue::new(SWAP_CAPACITY),
+                shutdown: AtomicBool::new(false),
+            }),
+            render: Mutex::new(SlotRender {
+                active: None,
+                fading: None,
+                fade_pos: 0,
+                fade_len: 480,
+                scratch: vec![[0.0; FADE_CHUNK]; MAX_FADE_CHANNELS],
+            }),
+            loader: Mutex::new(None),
+        }
+    }
+
+    // The model selected by the parameter. The one heard may lag behind while the loader is busy.
+    pub fn model(&self) -> usize {
+        self.shared.selector.requested.load(Ordering::Acquire) as usize
+    }
+
+    // A handle to the outcome of the slot's loads that stays usable after the slot is moved into a module
+    pub fn status(&self) -> SlotStatus {
+        SlotStatus { selector: self.shared.selector.clone() }
+    }
+}
+
+impl Algorithm for AlgorithmSlot {
+    type Params = ();
+
+    fn init(&mut self, fs: i32) {
+        self.shared.fs.store(fs, Ordering::Release);
+        let render = self.render.get_mut().unwrap_or_else(|e| e.into_inner());
+        render.fade_len = ((FADE_TIME * fs as f32) as usize).max(1);
+        // Algorithms the loader initialized with the previous rate are initialized again
+        while let Some(next) = self.shared.loaded.pop() {
+            if let Some(previous) = render.active.replace(next) {
+                self.shared.retire(previous);
+            }
+        }
+        if let Some(fading) = render.fading.take() {
+            self.shared.retire(fading);
+        }
+        if let Some(active) = render.active.as_mut() {
+            active.init(fs);
+        }
+    }
+
+    fn set_notifier(&mut self, notifier: ParamNotifier) {
+        let _ = self.shared.notifier.set(notifier);
+    }
+
+    fn get_parameters(&self, basename: &str, displayname: &str) -> (AlgoParamSet, ()) {
+        let mut root = AlgoParamSet::new(basename, displayname);
+        let models: Vec<&str> = self.shared.models.iter().map(|m| m.as_str()).collect();
+        let (setter, getter) = (self.shared.selector.clone(), self.shared.selector.clone());
+        let model = AlgoParam::builder(MODEL_KEY)
+            .name("Model")
+            .unit(AlgoParamUnit::INDEXED)
+            .value_strings(&models)
+            .setter(move |v| {
+                setter.requested.store(v as u32, Ordering::Release);
+                setter.wake_loader();
+            })
+            .getter(move || getter.requested.load(Ordering::Acquire) as f32)
+            .build()
+            .expect("The model parameter is valid");
+        root.add(AlgoParamNode::Param(model)).expect("A new set has room");
+
+        let initial = self.shared.selector.requested.load(Ordering::Acquire);
+        let set = match self.shared.instantiate(initial as usize) {
+            Ok((set, algo)) => {
+                self.render.lock().unwrap_or_else(|e| e.into_inner()).active = Some(algo);
+                self.shared.selector.report(LoadState::Loaded(initial as usize));
+                set
+            }
+            Err(e) => {
+                self.shared.selector.report(LoadState::Failed(initial as usize, e));
+                AlgoParamSet::new(ALGORITHM_KEY, "")
+            }
+        };
+        root.add(AlgoParamNode::ParamSet(set)).expect("A new set has room");
+
+        let mut loader = self.loader.lock().unwrap_or_else(|e| e.into_inner());
+        if loader.is_none() {
+            let shared = self.shared.clone();
+            if let Ok(handle) = thread::Builder::new().name("soundmodule-slot".to_string()).spawn(move || run_loader(shared, initial)) {
+                let _ = self.shared.selector.loader.set(handle.thread().clone());
+                *loader = Some(handle);
+            }
+        }
+        (root, ())
+    }
+
+    fn process(&self, _params: &(), outputs: &mut [&mut [f32]], inputs: &[&[f32]]) {
+        match self.render.try_lock() {
+            Ok(mut render) => render.render(&self.shared, outputs, inputs),
+            Err(_) => outputs.iter_mut().for_each(|channel| channel.fill(0.0)),
+        }
+    }
+
+    fn send_midi(&self, data: &[u8], timestamp: u64) {
+        if let Ok(render) = self.render.try_lock() && let Some(active) = render.active.as_ref() {
+            active.send_midi(data, timestamp);
+        }
+    }
+}
+
+impl Drop for AlgorithmSlot {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+        self.shared.selector.wake_loader();
+        if let Some(loader) = self.loader.get_mut().unwrap_or_else(|e| e.into_inner()).take() {
+            let _ = loader.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{registry::register_algorithm, util::AtomicF32, DynAlgorithm, SoundModule};
+
+    // Outputs the value of its only parameter
+    struct Constant {
+        key: &'static str,
+        default: f32,
+    }
+
+    impl Algorithm for Constant {
+        type Params = Arc<AtomicF32>;
+
+        fn init(&mut self, _fs: i32) {}
+
+        fn get_parameters(&self, basename: &str, displayname: &str) -> (AlgoParamSet, Arc<AtomicF32>) {
+            let value = Arc::new(AtomicF32::new(0.0));
+            let mut set = AlgoParamSet::new(basename, displayname);
+            set.add(AlgoParamNode::Param(AlgoParam::builder(self.key).range(0.0, 4.0).default(self.default).bind(&value).build().unwrap())).unwrap();
+            (set, value)
+        }
+
+        fn process(&self, params: &Arc<AtomicF32>, outputs: &mut [&mut [f32]], _inputs: &[&[f32]]) {
+            for channel in outputs.iter_mut() {
+                channel.fill(params.load(Ordering::Relaxed));
+            }
+        }
+
+        fn send_midi(&self, _data: &[u8], _timestamp: u64) {}
+    }
+
+    fn level() -> Box<dyn DynAlgorithm> {
+        Box::new(Constant { key: "level", default: 1.0 })
+    }
+
+    fn offset() -> Box<dyn DynAlgorithm> {
+        Box::new(Constant { key: "offset", default: 2.0 })
+    }
+
+    #[test]
+    fn test_algorithm_slot() {
+        register_algorithm("slot-test-level", level).unwrap();
+        register_algorithm("slot-test-offset", offset).unwrap();
+        let slot = AlgorithmSlot::new(&["slot-test-level", "slot-test-offset", "slot-test-missing"]);
+        let status = slot.status();
+        let (control, mut render) = SoundModule::new(slot).split();
+        assert_eq!(status.state(), LoadState::Loaded(0));
+        render.init(48000);
+        let mut out = [0.0; 64];
+        render.run(&mut [&mut out], &[]);
+        assert_eq!(out[63], 1.0);
+        assert!(control.param().address_of("algorithm.level").is_some());
+
+        let model = control.param().address_of("model").unwrap();
+        assert_eq!(control.format_parameter(model, 1.0).as_deref(), Some("slot-test-offset"));
+        control.set_parameter(model, 1.0).unwrap();
+        assert_eq!(status.wait(Duration::from_secs(10)), Some(LoadState::Loaded(1)));
+        let mut fade = Vec::new();
+        for _ in 0..10 {
+            render.run(&mut [&mut out], &[]);
+            fade.extend_from_slice(&out);
+        }
+        assert_eq!(out[0], 2.0);
+        assert_eq!(control.tree_generation(), 1);
+        assert!(control.param().address_of("algorithm.offset").is_some());
+        // About 480 samples from one to the other, without jumps
+        let fading = fade.iter().filter(|v| **v != 1.0 && **v != 2.0).count();
+        assert!((470..480).contains(&fading), "{fading}");
+        assert!(fade.windows(2).all(|w| (w[1] - w[0]).abs() < 0.01));
+
+        // Models that can't be created leave the slot as it is
+        control.set_parameter(model, 2.0).unwrap();
+        assert_eq!(status.wait(Duration::from_secs(10)), Some(LoadState::Failed(2, SlotError::UnknownAlgorithm)));
+        render.run(&mut [&mut out], &[]);
+        assert_eq!((out[0], control.tree_generation()), (2.0, 1));
+    }
+
+    #[test]
+    fn test_algorithm_slot_backlog() {
+        register_algorithm("slot-backlog-level", level).unwrap();
+        register_algorithm("slot-backlog-offset", offset).unwrap();
+        let slot = AlgorithmSlot::new(&["slot-backlog-level", "slot-backlog-offset"]);
+        let status = slot.status();
+        let (control, mut render) = SoundModule::new(slot).split();
+        render.init(48000);
+        let model = control.param().address_of("model").unwrap();
+
+        // More loads than fit between the loader and the render side, none taken by run
+        let loads = 2 * SWAP_CAPACITY + 1;
+        for i in 1..=loads {
+            let selected = i % 2;
+            control.set_parameter(model, selected as f32).unwrap();
+            assert_eq!(status.wait(Duration::from_secs(10)), Some(LoadState::Loaded(selected)));
+        }
+        assert_eq!(control.tree_generation(), loads as u64);
+        assert!(control.param().address_of("algorithm.offset").is_some());
+
+        // The render side ends up at the model the tree shows
+        let mut out = [0.0; 64];
+        for _ in 0..10 {
+            render.run(&mut [&mut out], &[]);
+        }
+        assert_eq!(out[63], 2.0);
+    }
+}
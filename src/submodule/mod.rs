@@ -0,0 +1,192 @@
+use crate::{algoparam::{AlgoParamNode, AlgoParamSet, OutOfRangeError}, notify::ParamNotifier, Algorithm};
+
+// A child algorithm inside another algorithm. The parent forwards the Algorithm calls to its submodules and
+// keeps their parameter storage in its own Params, e.g. type Params = (Gains, <Filter as Algorithm>::Params):
+//
+//   fn set_notifier(&mut self, notifier)     self.filter.set_notifier(&notifier)
+//   fn get_parameters(&self, ..)             let filter = self.filter.mount(&mut root)?
+//   fn init(&mut self, fs)                   self.filter.init(fs)
+//   fn process(&self, params, ..)            self.filter.process(&params.1, outputs, inputs)
+//
+// The child's set is mounted under the submodule identifier, so its parameters are found as
+// "identifier.param" in the parent's set. The child's state is the values of its parameters: reset,
+// save_state and restore_state work on the child's set inside the set it was mounted into.
+
+pub struct Submodule<A: Algorithm> {
+    identifier: String,
+    name: String,
+    algo: A,
+}
+
+impl<A: Algorithm> Submodule<A> {
+    pub fn new(identifier: &str, name: &str, algo: A) -> Submodule<A> {
+        Submodule { identifier: identifier.to_string(), name: name.to_string(), algo }
+    }
+
+    pub fn identifier(&self) -> &str {
+        &self.identifier
+    }
+
+    pub fn algorithm(&self) -> &A {
+        &self.algo
+    }
+
+    pub fn algorithm_mut(&mut self) -> &mut A {
+        &mut self.algo
+    }
+
+    // Gives the child a notifier whose keypaths are relative to its own set
+    pub fn set_notifier(&mut self, notifier: &ParamNotifier) {
+        self.algo.set_notifier(notifier.scoped(&self.identifier));
+    }
+
+    // Builds the child's set and adds it to parent. Returns the child's storage for process.
+    pub fn mount(&self, parent: &mut AlgoParamSet) -> Result<A::Params, OutOfRangeError> {
+        let (set, params) = self.parameters();
+        parent.add(AlgoParamNode::ParamSet(set))?;
+        Ok(params)
+    }
+
+    // The child's set and storage without mounting them, e.g. for ParamNotifier::replace_subtree
+    pub fn parameters(&self) -> (AlgoParamSet, A::Params) {
+        self.algo.get_parameters(&self.identifier, &self.name)
+    }
+
+    pub fn init(&mut self, fs: i32) {
+        self.algo.init(fs);
+    }
+
+    pub fn process(&self, params: &A::Params, outputs: &mut [&mut [f32]], inputs: &[&[f32]]) {
+        self.algo.process(params, outputs, inputs);
+    }
+
+    pub fn send_midi(&self, data: &[u8], timestamp: u64) {
+        self.algo.send_midi(data, timestamp);
+    }
+
+    // The child's set inside parent
+    pub fn param_set<'a>(&self, parent: &'a AlgoParamSet) -> Option<&'a AlgoParamSet> {
+        parent.children.iter().find_map(|child| match child {
+            AlgoParamNode::ParamSet(set) if set.identifier.as_bytes() == self.identifier.as_bytes() => Some(set),
+            _ => None,
+        })
+    }
+
+    // Sets the child's parameters back to their defaults. Returns the number of parameters reset.
+    pub fn reset(&self, parent: &AlgoParamSet) -> usize {
+        self.param_set(parent).map_or(0, AlgoParamSet::reset_to_defaults)
+    }
+
+    // The values of the child's writable parameters by keypath relative to its set, e.g. for saving with
+    // the parent's state
+    pub fn save_state(&self, parent: &AlgoParamSet) -> Vec<(String, f32)> {
+        let mut state = Vec::new();
+        if let Some(set) = self.param_set(parent) {
+            collect_values(set, "", &mut state);
+        }
+        state
+    }
+
+    // Sets the values saved by save_state, skipping keypaths the child doesn't have (anymore). Returns the
+    // number of values set.
+    pub fn restore_state(&self, parent: &AlgoParamSet, state: &[(String, f32)]) -> usize {
+        let Some(set) = self.param_set(parent) else { return 0 };
+        state.iter()
+            .filter(|(keypath, value)| set.address_of(keypath).is_some_and(|address| set.set(*value, address).is_ok()))
+            .count()
+    }
+}
+
+fn collect_values(set: &AlgoParamSet, prefix: &str, state: &mut Vec<(String, f32)>) {
+    for child in &set.children {
+        match child {
+            AlgoParamNode::Param(param) if !param.is_read_only() => {
+                state.push((format!("{}{}", prefix, param.identifier.to_string_lossy()), (param.getter)()));
+            }
+            AlgoParamNode::Param(_) => {}
+            AlgoParamNode::ParamSet(sub) => collect_values(sub, &format!("{}{}.", prefix, sub.identifier.to_string_lossy()), state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support::Gain, util::AtomicF32, SoundModule};
+    use std::sync::{Arc, OnceLock};
+
+    fn gain(seen: &Arc<OnceLock<u64>>) -> Gain {
+        Gain::new().report_address(seen)
+    }
+
+    // Left and right channel through their own Gain
+    struct Stereo {
+        left: Submodule<Gain>,
+        right: Submodule<Gain>,
+    }
+
+    impl Algorithm for Stereo {
+        type Params = (Arc<AtomicF32>, Arc<AtomicF32>);
+
+        fn init(&mut self, fs: i32) {
+            self.left.init(fs);
+            self.right.init(fs);
+        }
+
+        fn set_notifier(&mut self, notifier: ParamNotifier) {
+            self.left.set_notifier(&notifier);
+            self.right.set_notifier(&notifier);
+        }
+
+        fn get_parameters(&self, basename: &str, displayname: &str) -> (AlgoParamSet, Self::Params) {
+            let mut root = AlgoParamSet::new(basename, displayname);
+            let left = self.left.mount(&mut root).unwrap();
+            let right = self.right.mount(&mut root).unwrap();
+            (root, (left, right))
+        }
+
+        fn process(&self, params: &Self::Params, outputs: &mut [&mut [f32]], inputs: &[&[f32]]) {
+            let [left, right] = outputs else { return };
+            self.left.process(&params.0, &mut [left], &inputs[..1]);
+            self.right.process(&params.1, &mut [right], &inputs[1..]);
+        }
+
+        fn send_midi(&self, data: &[u8], timestamp: u64) {
+            self.right.send_midi(data, timestamp);
+        }
+    }
+
+    #[test]
+    fn test_submodule() {
+        let seen = Arc::new(OnceLock::new());
+        let module = SoundModule::new(Stereo {
+            left: Submodule::new("left", "Left", gain(&seen)),
+            right: Submodule::new("right", "Right", gain(&seen)),
+        });
+        let (control, mut render) = module.split();
+        let left = control.param().address_of("left.gain").unwrap();
+        let right = control.param().address_of("right.gain").unwrap();
+        control.set_parameter(left, 0.5).unwrap();
+        control.set_parameter(right, 2.0).unwrap();
+
+        render.init(48000);
+        let (mut out_l, mut out_r) = ([0.0; 4], [0.0; 4]);
+        render.run(&mut [&mut out_l, &mut out_r], &[&[1.0; 4], &[1.0; 4]]);
+        assert_eq!((out_l[0], out_r[0]), (0.5, 2.0));
+
+        // The child resolves its own keypaths inside its set
+        render.send_midi(&[0xb0, 7, 0], 0);
+        assert_eq!(seen.get(), Some(&right));
+
+        // State is saved, reset and restored per child
+        let stereo = Stereo { left: Submodule::new("left", "Left", gain(&seen)), right: Submodule::new("right", "Right", gain(&seen)) };
+        let tree = control.param();
+        let saved = stereo.left.save_state(&tree);
+        assert_eq!(saved, vec![("gain".to_string(), 0.5)]);
+        assert_eq!(stereo.left.reset(&tree), 1);
+        assert_eq!(control.get_parameter(left).unwrap(), 1.0);
+        assert_eq!(control.get_parameter(right).unwrap(), 2.0);
+        assert_eq!(stereo.left.restore_state(&tree, &saved), 1);
+        assert_eq!(control.get_parameter(left).unwrap(), 0.5);
+    }
+}
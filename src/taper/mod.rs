@@ -0,0 +1,129 @@
+// Mapping between a parameter value in min..max and a normalized 0..1 position, as used by hosts that
+// automate in normalized units (CLAP, VST3, MIDI CC) and by sliders.
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaperKind {
+    LINEAR,
+    LOGARITHMIC,
+    EXPONENTIAL,
+    SKEW,
+    STEPPED,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Taper {
+    #[default]
+    Linear,
+    // Equal ratios for equal distances (frequencies, times). Needs min > 0.
+    Logarithmic,
+    // value = min + (max - min) * normalized^exponent
+    Exponential(f32),
+    // Power curve that puts center at the normalized midpoint
+    Skew { center: f32 },
+    // Linear in count evenly spaced positions (count >= 2)
+    Stepped(u32),
+}
+
+impl Taper {
+    pub fn kind(&self) -> TaperKind {
+        match self {
+            Taper::Linear => TaperKind::LINEAR,
+            Taper::Logarithmic => TaperKind::LOGARITHMIC,
+            Taper::Exponential(_) => TaperKind::EXPONENTIAL,
+            Taper::Skew { .. } => TaperKind::SKEW,
+            Taper::Stepped(_) => TaperKind::STEPPED,
+        }
+    }
+
+    // The exponent, center or step count, 0 for the others. Exported to C next to the kind.
+    pub fn parameter(&self) -> f32 {
+        match self {
+            Taper::Linear | Taper::Logarithmic => 0.0,
+            Taper::Exponential(exponent) => *exponent,
+            Taper::Skew { center } => *center,
+            Taper::Stepped(count) => *count as f32,
+        }
+    }
+
+    pub fn is_valid_for(&self, min: f32, max: f32) -> bool {
+        match self {
+            Taper::Linear => true,
+            Taper::Logarithmic => min > 0.0 && max > min,
+            Taper::Exponential(exponent) => exponent.is_finite() && *exponent > 0.0,
+            Taper::Skew { center } => *center > min && *center < max,
+            Taper::Stepped(count) => *count >= 2,
+        }
+    }
+
+    pub fn to_normalized(&self, value: f32, min: f32, max: f32) -> f32 {
+        if max <= min {
+            return 0.0;
+        }
+        let value = value.max(min).min(max);
+        let linear = (value - min) / (max - min);
+        let normalized = match self {
+            Taper::Linear => linear,
+            Taper::Logarithmic => (value / min).ln() / (max / min).ln(),
+            Taper::Exponential(exponent) => linear.powf(1.0 / exponent),
+            Taper::Skew { center } => linear.powf(1.0 / skew_exponent(*center, min, max)),
+            Taper::Stepped(count) => quantize(linear, *count),
+        };
+        normalized.clamp(0.0, 1.0)
+    }
+
+    pub fn from_normalized(&self, normalized: f32, min: f32, max: f32) -> f32 {
+        let n = if normalized.is_nan() { 0.0 } else { normalized.clamp(0.0, 1.0) };
+        let value = match self {
+            Taper::Linear => min + n * (max - min),
+            Taper::Logarithmic => min * (max / min).powf(n),
+            Taper::Exponential(exponent) => min + (max - min) * n.powf(*exponent),
+            Taper::Skew { center } => min + (max - min) * n.powf(skew_exponent(*center, min, max)),
+            Taper::Stepped(count) => min + quantize(n, *count) * (max - min),
+        };
+        // Not clamp, which panics on an inverted or NaN range
+        value.max(min.min(max)).min(max.max(min))
+    }
+}
+
+fn skew_exponent(center: f32, min: f32, max: f32) -> f32 {
+    ((center - min) / (max - min)).ln() / 0.5f32.ln()
+}
+
+fn quantize(n: f32, count: u32) -> f32 {
+    let steps = count.max(2) as f32 - 1.0;
+    (n * steps).round() / steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tapers() {
+        let log = Taper::Logarithmic;
+        assert!((log.from_normalized(0.5, 20.0, 20000.0) - 632.456).abs() < 0.01);
+        assert!((log.to_normalized(632.456, 20.0, 20000.0) - 0.5).abs() < 1e-5);
+
+        let skew = Taper::Skew { center: 1000.0 };
+        assert!((skew.from_normalized(0.5, 20.0, 20000.0) - 1000.0).abs() < 0.01);
+        assert!((skew.to_normalized(1000.0, 20.0, 20000.0) - 0.5).abs() < 1e-5);
+
+        let exp = Taper::Exponential(2.0);
+        assert_eq!(exp.from_normalized(0.5, 0.0, 4.0), 1.0);
+        assert_eq!(exp.to_normalized(1.0, 0.0, 4.0), 0.5);
+
+        let stepped = Taper::Stepped(5);
+        assert_eq!(stepped.from_normalized(0.3, 0.0, 4.0), 1.0);
+        assert_eq!(stepped.to_normalized(3.2, 0.0, 4.0), 0.75);
+
+        assert_eq!(Taper::Linear.to_normalized(100.0, 0.0, 10.0), 1.0);
+        assert!(!Taper::Logarithmic.is_valid_for(0.0, 1.0));
+        assert!(!Taper::Skew { center: 30.0 }.is_valid_for(0.0, 10.0));
+
+        // Inverted or NaN ranges don't panic
+        assert_eq!(Taper::Linear.from_normalized(0.25, 4.0, 0.0), 3.0);
+        assert_eq!(Taper::Linear.to_normalized(1.0, 4.0, 0.0), 0.0);
+        assert!(Taper::Linear.to_normalized(1.0, f32::NAN, 4.0).is_nan());
+    }
+}
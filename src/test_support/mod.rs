@@ -0,0 +1,96 @@
+use std::sync::{atomic::Ordering, Arc, OnceLock};
+
+use crate::{algoparam::{AlgoParam, AlgoParamNode, AlgoParamSet, AlgoParamUnit}, notify::ParamNotifier, util::AtomicF32, Algorithm};
+
+// Algorithms shared by the unit tests
+
+// Multiplies its inputs by its "gain" parameter, 0..2 and 1 unless configured otherwise
+pub struct Gain {
+    default: f32,
+    max: f32,
+    // Set the parameters are nested in, e.g. "stage" for "stage.gain"
+    set: Option<&'static str>,
+    // process panics at this gain or above
+    panic_at: Option<f32>,
+    // Adds a BOOLEAN "trip" parameter whose setter panics when switched on
+    trip: bool,
+    // Receives the address the notifier resolves "gain" to on any MIDI message
+    seen: Option<Arc<OnceLock<u64>>>,
+    notifier: Option<ParamNotifier>,
+}
+
+impl Gain {
+    pub fn new() -> Gain {
+        Gain { default: 1.0, max: 2.0, set: None, panic_at: None, trip: false, seen: None, notifier: None }
+    }
+
+    pub fn range(mut self, default: f32, max: f32) -> Gain {
+        (self.default, self.max) = (default, max);
+        self
+    }
+
+    pub fn in_set(mut self, set: &'static str) -> Gain {
+        self.set = Some(set);
+        self
+    }
+
+    pub fn panic_at(mut self, gain: f32) -> Gain {
+        self.panic_at = Some(gain);
+        self
+    }
+
+    pub fn with_trip(mut self) -> Gain {
+        self.trip = true;
+        self
+    }
+
+    pub fn report_address(mut self, seen: &Arc<OnceLock<u64>>) -> Gain {
+        self.seen = Some(seen.clone());
+        self
+    }
+}
+
+impl Algorithm for Gain {
+    type Params = Arc<AtomicF32>;
+
+    fn init(&mut self, _fs: i32) {}
+
+    fn set_notifier(&mut self, notifier: ParamNotifier) {
+        self.notifier = Some(notifier);
+    }
+
+    fn get_parameters(&self, basename: &str, displayname: &str) -> (AlgoParamSet, Arc<AtomicF32>) {
+        let gain = Arc::new(AtomicF32::new(self.default));
+        let mut params = AlgoParamSet::new(self.set.unwrap_or(basename), self.set.unwrap_or(displayname));
+        params.add(AlgoParamNode::Param(AlgoParam::builder("gain").name("Gain").unit(AlgoParamUnit::LINEARGAIN)
+            .range(0.0, self.max).default(self.default).bind(&gain).build().unwrap())).unwrap();
+        if self.trip {
+            params.add(AlgoParamNode::Param(AlgoParam::builder("trip").unit(AlgoParamUnit::BOOLEAN)
+                .setter(|v| assert!(v < 0.5, "tripped")).getter(|| 0.0).build().unwrap())).unwrap();
+        }
+        if self.set.is_none() {
+            return (params, gain);
+        }
+        let mut root = AlgoParamSet::new(basename, displayname);
+        root.add(AlgoParamNode::ParamSet(params)).unwrap();
+        (root, gain)
+    }
+
+    fn process(&self, params: &Arc<AtomicF32>, outputs: &mut [&mut [f32]], inputs: &[&[f32]]) {
+        let gain = params.load(Ordering::Relaxed);
+        if let Some(limit) = self.panic_at {
+            assert!(gain < limit, "gain too high");
+        }
+        for (output, input) in outputs.iter_mut().zip(inputs) {
+            for (o, i) in output.iter_mut().zip(input.iter()) {
+                *o = gain * i;
+            }
+        }
+    }
+
+    fn send_midi(&self, _data: &[u8], _timestamp: u64) {
+        if let Some(seen) = &self.seen && let Some(address) = self.notifier.as_ref().and_then(|n| n.address_of("gain")) {
+            let _ = seen.set(address);
+        }
+    }
+}
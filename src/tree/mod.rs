@@ -0,0 +1,255 @@
+use std::{fmt, ptr, sync::{atomic::{AtomicPtr, AtomicU64, Ordering}, Arc, Mutex}, thread};
+
+use crate::algoparam::AlgoParamSet;
+
+// The parameter tree of a module, which the algorithm may change while the module runs (e.g. to swap a
+// sub-algorithm). Trees are never modified in place: replace_subtree builds a new tree from a copy of the
+// current one, so anybody holding an Arc to the old tree can keep using it. Every change increments the
+// generation, hosts compare it to the one they built their parameter model from.
+//
+// Reading the current tree (read, get) never locks, so the render side and ParamNotifier may use it on
+// the audio thread. Each reader holds one of MAX_READERS slots with the generation it entered at. A
+// replaced tree is freed, on the control side and never by a reader, once no slot holds its generation
+// or an older one, so readers of newer trees don't keep it alive.
+
+// Threads that may be inside read at the same time. Further readers wait for a slot.
+pub const MAX_READERS: usize = 64;
+// Reader slot not in use
+const IDLE: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TreeError {
+    UnknownSet,
+    // The tree has not been built yet, e.g. when called from Algorithm::get_parameters
+    NotAttached,
+}
+
+impl fmt::Display for TreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeError::UnknownSet => write!(f, "No parameter set at that keypath"),
+            TreeError::NotAttached => write!(f, "The parameter tree has not been built yet"),
+        }
+    }
+}
+
+impl std::error::Error for TreeError {}
+
+struct Version {
+    tree: Arc<AlgoParamSet>,
+    generation: u64,
+}
+
+pub struct ParamTree {
+    current: AtomicPtr<Version>,
+    // Generation of current, stored after it is swapped in, so it may lag behind for a moment
+    generation: AtomicU64,
+    // Generation each reader entered at, or IDLE
+    readers: [AtomicU64; MAX_READERS],
+    // Serializes replace_subtree and keeps the replaced versions until they can be freed
+    retired: Mutex<Vec<Version>>,
+}
+
+// Leaves read even if f panics
+struct ReadGuard<'a>(&'a AtomicU64);
+
+impl Drop for ReadGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(IDLE, Ordering::SeqCst);
+    }
+}
+
+impl ParamTree {
+    pub fn new(root: AlgoParamSet) -> Arc<ParamTree> {
+        let version = Box::new(Version { tree: Arc::new(root), generation: 0 });
+        Arc::new(ParamTree {
+            current: AtomicPtr::new(Box::into_raw(version)),
+            generation: AtomicU64::new(0),
+            readers: std::array::from_fn(|_| AtomicU64::new(IDLE)),
+            retired: Mutex::new(Vec::new()),
+        })
+    }
+
+    // Runs f with the current tree and its generation, without locking or allocating
+    pub fn read<R>(&self, f: impl FnOnce(&Arc<AlgoParamSet>, u64) -> R) -> R {
+        // The slot is taken before loading the pointer (all SeqCst). The generation is never newer than the
+        // version loaded after it, and a writer that didn't see the slot has already swapped out any
+        // version it frees, so the version loaded here stays alive until the slot is given back.
+        let slot = self.enter(self.generation.load(Ordering::SeqCst));
+        let _guard = ReadGuard(slot);
+        let version = unsafe { &*self.current.load(Ordering::SeqCst) };
+        f(&version.tree, version.generation)
+    }
+
+    fn enter(&self, generation: u64) -> &AtomicU64 {
+        loop {
+            for slot in self.readers.iter() {
+                if slot.compare_exchange(IDLE, generation, Ordering::SeqCst, Ordering::Relaxed).is_ok() {
+                    return slot;
+                }
+            }
+            thread::yield_now();
+        }
+    }
+
+    // The current tree
+    pub fn get(&self) -> Arc<AlgoParamSet> {
+        self.read(|tree, _| tree.clone())
+    }
+
+    // Starts at 0 and increments with every change of the tree
+    pub fn generation(&self) -> u64 {
+        self.read(|_, generation| generation)
+    }
+
+    // Replaces the set at keypath (e.g. "slot" or "chain.slot") with set, keeping the identifier of the
+    // replaced set so addresses and keypaths outside of it stay the same. Returns the address of the set
+    // (see AlgoParamSet::replace_subtree) and the new generation. Not for the audio thread.
+    pub fn replace_subtree(&self, keypath: &str, set: AlgoParamSet) -> Result<(u64, u64), TreeError> {
+        let mut retired = self.retired.lock().unwrap_or_else(|e| e.into_inner());
+        // Only freed by writers, which hold the lock
+        let current = unsafe { &*self.current.load(Ordering::SeqCst) };
+        let mut tree = AlgoParamSet::clone(&current.tree);
+        let address = tree.replace_subtree(keypath, set).map_err(|_| TreeError::UnknownSet)?;
+        let generation = current.generation + 1;
+        let version = Box::new(Version { tree: Arc::new(tree), generation });
+        let old = self.current.swap(Box::into_raw(version), Ordering::SeqCst);
+        self.generation.store(generation, Ordering::SeqCst);
+        retired.push(*unsafe { Box::from_raw(old) });
+        self.free_retired(&mut retired);
+        Ok((address, generation))
+    }
+
+    // Control side: frees the replaced trees nobody can be reading anymore. Doesn't wait for a concurrent
+    // replace_subtree.
+    pub fn collect(&self) {
+        if let Ok(mut retired) = self.retired.try_lock() {
+            self.free_retired(&mut retired);
+        }
+    }
+
+    // Frees the versions older than the oldest generation a reader entered at
+    fn free_retired(&self, retired: &mut Vec<Version>) {
+        if retired.is_empty() {
+            return;
+        }
+        let oldest = self.readers.iter().map(|slot| slot.load(Ordering::SeqCst)).min().unwrap_or(IDLE);
+        retired.retain(|version| version.generation >= oldest);
+    }
+}
+
+impl Drop for ParamTree {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.current.swap(ptr::null_mut(), Ordering::SeqCst)) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+    use crate::{algoparam::{AlgoParam, AlgoParamNode}, event::ParamEvent, notify::{ParamChange, ParamNotifier}, util::AtomicF32, Algorithm, SoundModule};
+
+    fn stage(name: &str, params: &[&str], storage: &Arc<AtomicF32>) -> AlgoParamSet {
+        let mut set = AlgoParamSet::new(name, name);
+        for param in params {
+            set.add(AlgoParamNode::Param(AlgoParam::builder(param).bind(storage).build().unwrap())).unwrap();
+        }
+        set
+    }
+
+    // Shares its notifier, so the test can rebuild the "slot" set like a loader thread of the algorithm would
+    struct Swapper {
+        level: Arc<AtomicF32>,
+        notifier: Arc<OnceLock<ParamNotifier>>,
+    }
+
+    impl Algorithm for Swapper {
+        type Params = ();
+
+        fn init(&mut self, _fs: i32) {}
+
+        fn set_notifier(&mut self, notifier: ParamNotifier) {
+            let _ = self.notifier.set(notifier);
+        }
+
+        fn get_parameters(&self, basename: &str, displayname: &str) -> (AlgoParamSet, ()) {
+            let mut root = AlgoParamSet::new(basename, displayname);
+            root.add(AlgoParamNode::Param(AlgoParam::builder("level").bind(&self.level).build().unwrap())).unwrap();
+            root.add(AlgoParamNode::ParamSet(stage("slot", &["drive"], &self.level))).unwrap();
+            (root, ())
+        }
+
+        fn process(&self, _params: &(), _outputs: &mut [&mut [f32]], _inputs: &[&[f32]]) {}
+
+        fn send_midi(&self, _data: &[u8], _timestamp: u64) {}
+    }
+
+    #[test]
+    fn test_replace_subtree() {
+        let (level, notifier) = (Arc::new(AtomicF32::new(0.0)), Arc::new(OnceLock::new()));
+        let module = SoundModule::new(Swapper { level: level.clone(), notifier: notifier.clone() });
+        let (control, mut render) = module.split();
+        let old = control.param();
+        let address = old.address_of("level").unwrap();
+        assert_eq!(control.tree_generation(), 0);
+        assert!(old.address_of("slot.drive").is_some());
+
+        // Scheduled against the old tree, so dropped once it is replaced
+        control.schedule_parameter(ParamEvent { address: old.address_of("slot.drive").unwrap(), value: 0.25, sample_time: 0, ramp: 0 }).unwrap();
+
+        let notifier = notifier.get().unwrap();
+        assert_eq!(notifier.replace_subtree("missing", AlgoParamSet::new("x", "x")), Err(TreeError::UnknownSet));
+        assert_eq!(notifier.replace_subtree("slot", stage("delay", &["time", "feedback"], &level)), Ok(1));
+        assert_eq!(control.tree_generation(), 1);
+        let tree = control.param();
+        assert_eq!(tree.address_of("level"), Some(address));
+        assert!(tree.address_of("slot.drive").is_none());
+        let feedback = tree.address_of("slot.feedback").unwrap();
+        assert_eq!(control.pop_change(), Some(ParamChange::TreeChanged { address: 0x01ff_ffff_ffff_ffff }));
+        // Copies that are still in use are unaffected
+        assert!(old.address_of("slot.drive").is_some());
+
+        // The render side picks up the new tree at the next block
+        control.set_parameter(feedback, 0.5).unwrap();
+        assert_eq!(control.get_parameter(address).unwrap(), 0.5);
+        render.run(&mut [&mut [0.0; 16]], &[]);
+        assert_eq!(control.get_parameter(address).unwrap(), 0.5);
+        let time = tree.address_of("slot.time").unwrap();
+        control.schedule_parameter(ParamEvent { address: time, value: 0.75, sample_time: 20, ramp: 0 }).unwrap();
+        render.run(&mut [&mut [0.0; 16]], &[]);
+        assert_eq!(control.get_parameter(time).unwrap(), 0.75);
+    }
+
+    #[test]
+    fn test_reclaim() {
+        let level = Arc::new(AtomicF32::new(0.0));
+        let mut root = AlgoParamSet::new("root", "Root");
+        root.add(AlgoParamNode::ParamSet(stage("slot", &["drive"], &level))).unwrap();
+        let tree = ParamTree::new(root);
+
+        // Kept while a reader may still use it
+        let first = tree.read(|old, _| {
+            let first = Arc::downgrade(old);
+            tree.replace_subtree("slot", stage("delay", &["time"], &level)).unwrap();
+            tree.collect();
+            assert!(first.upgrade().is_some());
+            first
+        });
+
+        // Freed once its readers are gone, also while readers of the newer tree keep going
+        let second = tree.read(|current, generation| {
+            assert_eq!(generation, 1);
+            tree.collect();
+            assert!(first.upgrade().is_none());
+            let second = Arc::downgrade(current);
+            tree.replace_subtree("slot", stage("drive", &["gain"], &level)).unwrap();
+            second
+        });
+        tree.read(|_, generation| {
+            assert_eq!(generation, 2);
+            tree.collect();
+            assert!(second.upgrade().is_none());
+        });
+    }
+}
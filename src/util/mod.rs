@@ -1,4 +1,20 @@
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::{cell::UnsafeCell, cmp::min, ffi::c_char, mem::MaybeUninit, sync::atomic::{AtomicU32, AtomicUsize, Ordering}};
+
+/// Copies bytes into a C buffer of len bytes, truncating if needed and always null terminating.
+/// Returns the full length excluding the terminator, like snprintf. buf may be NULL to query the length.
+///
+/// # Safety
+/// buf must be NULL or point to len writable bytes.
+pub unsafe fn copy_to_c_buffer(bytes: &[u8], buf: *mut c_char, len: usize) -> usize {
+    if !buf.is_null() && len > 0 {
+        let n = min(bytes.len(), len - 1);
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr() as *const c_char, buf, n);
+            *buf.add(n) = 0;
+        }
+    }
+    bytes.len()
+}
 
 
 
@@ -146,3 +162,227 @@ impl Smooth {
     }
 
 }
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeterMode {
+    Peak,
+    Rms,
+}
+
+// Level reading written by the audio thread and polled by the UI, usually through a READ_ONLY | METER
+// parameter. Readings above the current level are taken immediately and held for hold seconds, after
+// that the level falls at decay dB per second. Levels are linear amplitudes.
+// Only the audio thread may call process/update, any thread may read.
+#[derive(Debug)]
+pub struct Meter {
+    mode: MeterMode,
+    hold: f32,
+    decay: f32,
+    level: AtomicF32,
+    hold_left: AtomicF32,
+    mean_square: AtomicF32,
+}
+
+// Time constant of the RMS average in seconds
+const RMS_WINDOW: f32 = 0.3;
+
+impl Meter {
+    pub fn new(mode: MeterMode) -> Meter {
+        Meter {
+            mode,
+            hold: 0.0,
+            decay: 20.0,
+            level: AtomicF32::new(0.0),
+            hold_left: AtomicF32::new(0.0),
+            mean_square: AtomicF32::new(0.0),
+        }
+    }
+
+    pub fn with_hold(mut self, seconds: f32) -> Meter {
+        self.hold = seconds.max(0.0);
+        self
+    }
+
+    // A decay of infinity makes the meter follow the reading directly
+    pub fn with_decay(mut self, db_per_second: f32) -> Meter {
+        self.decay = db_per_second.max(0.0);
+        self
+    }
+
+    // Meters a block of samples at sample rate fs
+    pub fn process(&self, samples: &[f32], fs: f32) {
+        if samples.is_empty() || fs <= 0.0 {
+            return;
+        }
+        let reading = match self.mode {
+            MeterMode::Peak => samples.iter().fold(0.0f32, |m, x| m.max(x.abs())),
+            MeterMode::Rms => {
+                let coef = 1.0 - (-1.0 / (RMS_WINDOW * fs)).exp();
+                let mut ms = self.mean_square.load(Ordering::Relaxed);
+                for x in samples {
+                    ms += (x * x - ms) * coef;
+                }
+                self.mean_square.store(ms, Ordering::Relaxed);
+                ms.sqrt()
+            },
+        };
+        self.update(reading, samples.len() as f32 / fs);
+    }
+
+    // Feeds a reading computed by the algorithm, e.g. gain reduction, covering elapsed seconds
+    pub fn update(&self, reading: f32, elapsed: f32) {
+        let reading = if reading.is_finite() { reading.abs() } else { 0.0 };
+        let level = self.level.load(Ordering::Relaxed);
+        let hold_left = self.hold_left.load(Ordering::Relaxed);
+        if reading >= level {
+            self.level.store(reading, Ordering::Relaxed);
+            self.hold_left.store(self.hold, Ordering::Relaxed);
+        } else if hold_left > 0.0 {
+            self.hold_left.store(hold_left - elapsed, Ordering::Relaxed);
+        } else {
+            let decayed = level * 10.0f32.powf(-self.decay * elapsed / 20.0);
+            self.level.store(decayed.max(reading), Ordering::Relaxed);
+        }
+    }
+
+    pub fn level(&self) -> f32 {
+        self.level.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.level.store(0.0, Ordering::Relaxed);
+        self.hold_left.store(0.0, Ordering::Relaxed);
+        self.mean_square.store(0.0, Ordering::Relaxed);
+    }
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Meter::new(MeterMode::Peak)
+    }
+}
+
+
+// Bounded lock-free queue after Dmitry Vyukov's MPMC design. push and pop never block or allocate, so
+// either end may be used on the audio thread. Each slot carries a sequence number telling whether it is
+// ready to be written (sequence == position) or read (sequence == position + 1).
+pub struct BoundedQueue<T> {
+    slots: Box<[Slot<T>]>,
+    mask: usize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+struct Slot<T> {
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// A slot's value is only accessed by the one thread that won it through head/tail
+unsafe impl<T: Send> Send for BoundedQueue<T> {}
+unsafe impl<T: Send> Sync for BoundedQueue<T> {}
+
+impl<T> BoundedQueue<T> {
+    // Capacity is rounded up to a power of two
+    pub fn new(capacity: usize) -> BoundedQueue<T> {
+        let capacity = capacity.max(2).next_power_of_two();
+        let slots = (0..capacity)
+            .map(|i| Slot { sequence: AtomicUsize::new(i), value: UnsafeCell::new(MaybeUninit::uninit()) })
+            .collect();
+        BoundedQueue { slots, mask: capacity - 1, head: AtomicUsize::new(0), tail: AtomicUsize::new(0) }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    // Hands the value back if the queue is full
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let diff = slot.sequence.load(Ordering::Acquire).wrapping_sub(pos) as isize;
+            if diff == 0 {
+                match self.tail.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    },
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                return Err(value);
+            } else {
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let diff = slot.sequence.load(Ordering::Acquire).wrapping_sub(pos.wrapping_add(1)) as isize;
+            if diff == 0 {
+                match self.head.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.sequence.store(pos.wrapping_add(self.mask + 1), Ordering::Release);
+                        return Some(value);
+                    },
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                return None;
+            } else {
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<T> Drop for BoundedQueue<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn test_bounded_queue() {
+        let queue = BoundedQueue::new(3);
+        assert_eq!(queue.capacity(), 4);
+        for i in 0..4 {
+            queue.push(i).unwrap();
+        }
+        assert_eq!(queue.push(4), Err(4));
+        assert_eq!(queue.pop(), Some(0));
+
+        // Two producers, one consumer. Every value arrives once, in order per producer.
+        let queue = Arc::new(BoundedQueue::<(usize, usize)>::new(16));
+        let producers: Vec<_> = (0..2).map(|p| {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    while queue.push((p, i)).is_err() {
+                        thread::yield_now();
+                    }
+                }
+            })
+        }).collect();
+        let mut next = [0, 0];
+        while next != [1000, 1000] {
+            if let Some((p, i)) = queue.pop() {
+                assert_eq!(i, next[p]);
+                next[p] += 1;
+            }
+        }
+        producers.into_iter().for_each(|t| t.join().unwrap());
+    }
+}
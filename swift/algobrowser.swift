@@ -11,8 +11,11 @@ class AlgoParam {
     let address: UInt64  // ← the value of `basekey` after the call
     let dependents: [String]
     var dependentAddresses: [UInt64]
+    let valueStrings: [String]?
+    let unitName: String?
+    let flags: UInt32
     
-    init(key: String, name: String, min: Float, max: Float, unit: AudioUnitParameterUnit, address: UInt64, dependents: [String], dependentAddresses: [UInt64]) {
+    init(key: String, name: String, min: Float, max: Float, unit: AudioUnitParameterUnit, address: UInt64, dependents: [String], dependentAddresses: [UInt64], valueStrings: [String]?, unitName: String?, flags: UInt32) {
         self.key = key
         self.name = name
         self.min = min
@@ -21,10 +24,28 @@ class AlgoParam {
         self.address = address
         self.dependents = dependents
         self.dependentAddresses = dependentAddresses
+        self.valueStrings = valueStrings
+        self.unitName = unitName
+        self.flags = flags
+    }
+
+    // ALGO_PARAM_* flags as AudioUnit parameter flags. AU has no hidden flag, expert mode is the closest.
+    var auFlags: AudioUnitParameterOptions {
+        var options: AudioUnitParameterOptions = [.flag_IsReadable]
+        if flags & ALGO_PARAM_READ_ONLY == 0 {
+            options.insert(.flag_IsWritable)
+        } else if flags & ALGO_PARAM_METER != 0 {
+            options.insert(.flag_MeterReadOnly)
+        }
+        if flags & ALGO_PARAM_HIDDEN != 0 { options.insert(.flag_ExpertMode) }
+        if flags & ALGO_PARAM_NON_AUTOMATABLE != 0 { options.insert(.flag_NonRealTime) }
+        if flags & ALGO_PARAM_GLOBAL != 0 { options.insert(.flag_IsGlobalMeta) }
+        if flags & ALGO_PARAM_RAMPABLE != 0 { options.insert(.flag_CanRamp) }
+        return options
     }
     
     func asAUParameter() -> AUParameter {
-        AUParameterTree.createParameter(withIdentifier: key, name: name, address: address, min: min, max: max, unit: unit, unitName: nil, valueStrings: nil, dependentParameters: dependentAddresses.map( { NSNumber.init(value:$0) }))
+        AUParameterTree.createParameter(withIdentifier: key, name: name, address: address, min: min, max: max, unit: unit, unitName: unitName, flags: auFlags, valueStrings: valueStrings, dependentParameters: dependentAddresses.map( { NSNumber.init(value:$0) }))
     }
 }
 
@@ -90,6 +111,18 @@ class AlgoBrowser {
         return mapCParam(raw, address: key)
     }
 
+    private func stringList(_ rawList: UnsafeMutablePointer<UnsafePointer<CChar>?>?) -> [String] {
+        var strings: [String] = []
+        if let rawList = rawList {
+            var ptr = rawList
+            while let item = ptr.pointee {
+                strings.append(String(cString: item))
+                ptr = ptr.advanced(by: 1)
+            }
+        }
+        return strings
+    }
+
     private func mapCParam(_ cparam: AlgoCParam, address: UInt64) -> AlgoParam? {
         // Use sentinel to detect invalid result
         guard address != NOT_FOUND else {
@@ -100,14 +133,9 @@ class AlgoBrowser {
         let name = String(cString: cparam.name)
         let unit = AudioUnitParameterUnit(rawValue: UInt32(cparam.dtype)) ?? .generic
 
-        var dependents: [String] = []
-        if let rawList = cparam.dependents {
-            var ptr = rawList
-            while let item = ptr.pointee {
-                dependents.append(String(cString: item))
-                ptr = ptr.advanced(by: 1)
-            }
-        }
+        let dependents = stringList(cparam.dependents)
+        let valueStrings = cparam.value_strings != nil ? stringList(cparam.value_strings) : nil
+        let unitName = cparam.unit_name.map { String(cString: $0) }
 
         return AlgoParam(
             key: key, 
@@ -117,7 +145,10 @@ class AlgoBrowser {
             unit: unit, 
             address: address, 
             dependents: dependents,
-            dependentAddresses: []
+            dependentAddresses: [],
+            valueStrings: valueStrings,
+            unitName: unitName,
+            flags: cparam.flags
         )
     }
     
@@ -69,6 +69,7 @@ struct ParameterView: View {
         case .relativeSemiTones: return "st"
         case .linearGain: return "x"
         case .degrees: return "°"
+        case .customUnit: return param.parameter?.unitName ?? ""
         default: return ""
         }
     }
@@ -96,7 +97,10 @@ struct ObservableAUParameterNodeView: View {
 
     var body: some View {
         if let param = node as? ObservableAUParameter {
-            ParameterView(param: param)
+            // Hidden parameters are exported as expert mode
+            if !(param.parameter?.flags.contains(.flag_ExpertMode) ?? false) {
+                ParameterView(param: param)
+            }
         } else if let group = node as? ObservableAUParameterGroup {
             CollapsibleGroup(group: group)
         }